@@ -1,39 +1,437 @@
 use std::{
     path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
     time::Instant,
 };
 
-use ractor::{Actor, ActorProcessingErr, ActorRef, concurrency::Duration};
-use tracing::log;
+use ractor::{
+    Actor, ActorProcessingErr, ActorRef, MessagingErr, RpcReplyPort,
+    concurrency::{Duration, JoinHandle},
+};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::sync::{
+    Semaphore, broadcast,
+    mpsc::{Sender, error::TrySendError},
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{Span, instrument, log};
+
+use crate::crates_index::{DiffAction, DiffErrorMode};
+use crate::git::{
+    AuthConfig, CloneOptions, DEFAULT_COMMAND_TIMEOUT, DEFAULT_GIT_BINARY, DEFAULT_REMOTE,
+    DiffAlgorithm, GitBackend, GitBackendKind, GitError, GitService, RetryPolicy,
+};
+#[cfg(feature = "libgit2")]
+use crate::git2_backend::Git2Backend;
+use crate::state::{EmittedRange, PollState};
+#[cfg(feature = "sqlite")]
+use crate::store::{DiffStore, StoreError};
+use crate::webhook::Webhook;
+
+/// Error of a single index cycle.
+#[derive(Debug)]
+pub enum IndexError {
+    Git(GitError),
+    #[cfg(feature = "sqlite")]
+    Store(StoreError),
+    /// A revision passed to [`IndexerActorMessage::IndexRange`] doesn't resolve to a commit.
+    UnknownRevision(String),
+}
+
+impl From<GitError> for IndexError {
+    fn from(err: GitError) -> Self {
+        IndexError::Git(err)
+    }
+}
 
-use crate::git::GitService;
+#[cfg(feature = "sqlite")]
+impl From<StoreError> for IndexError {
+    fn from(err: StoreError) -> Self {
+        IndexError::Store(err)
+    }
+}
 
 #[derive(Debug)]
 pub enum IndexerActorMessage {
+    /// Runs an index, auto-indexing keeps its schedule.
     Index,
+    /// Runs an index and, while auto-indexing, schedules the next tick one interval from
+    /// now instead of keeping the old schedule.
+    IndexNow,
+    /// Timer tick of auto-indexing, runs an index and schedules the next tick.
+    ///
+    /// The actor handles one message at a time, so an index run never overlaps another one.
+    /// The next tick is only scheduled once the current run finished, which keeps a slow
+    /// run from queueing up ticks. A tick arriving while the next one is still scheduled is
+    /// dropped.
     AutoIndex(Duration),
     StartAutoIndex(Duration),
     StopAutoIndex,
+    /// Diffs two arbitrary revisions and emits their actions, e.g. to backfill history.
+    ///
+    /// Leaves `last_commit_hash` and auto-indexing untouched. Running the range that was
+    /// emitted last again does nothing.
+    IndexRange {
+        from: String,
+        to: String,
+    },
+    /// Stops auto-indexing but remembers its interval for [`IndexerActorMessage::Resume`].
+    Pause,
+    /// Restarts auto-indexing at the interval it had when it was paused.
+    Resume,
+    /// Registers a channel that receives the [`IndexerEvent`]s from now on.
+    Subscribe(Sender<IndexerEvent>),
+    /// Replies with a snapshot of the actor's state, use with [`ractor::call!`].
+    GetStatus(RpcReplyPort<IndexerStatus>),
+}
+
+/// All actions of one index cycle, delivered at once to the store, the webhook and
+/// subscribers.
+#[derive(Debug, Clone, Serialize)]
+pub struct DiffBatch {
+    pub repository: String,
+    /// `None` for an initial index without a previous commit.
+    pub old_commit: Option<String>,
+    pub new_commit: String,
+    pub actions: Vec<DiffAction>,
+}
+
+/// Sent to the subscribers of an [`IndexerActor`].
+#[derive(Debug, Clone)]
+pub enum IndexerEvent {
+    /// The initial clone finished, `head` is the commit it was cloned at, `None` for an
+    /// empty repository.
+    Cloned { repo: String, head: Option<String> },
+    /// The first commit hash is known, changes are indexed from `head` on.
+    Ready { repo: String, head: String },
+    /// An index cycle found changes.
+    Changes(DiffBatch),
+    /// The remote's history was rewritten, `old_commit` is no ancestor of `new_commit`.
+    HistoryRewrite {
+        repo: String,
+        old_commit: String,
+        new_commit: String,
+    },
+}
+
+/// Snapshot of an [`IndexerActor`], returned by [`IndexerActorMessage::GetStatus`].
+#[derive(Debug, Clone)]
+pub struct IndexerStatus {
+    pub last_indexed: Option<Instant>,
+    pub last_commit_hash: Option<String>,
+    pub auto_indexing: bool,
+    pub timer_interval: Option<Duration>,
+    /// Interval auto-indexing resumes at, set while paused.
+    pub paused_interval: Option<Duration>,
+    pub consecutive_failures: u32,
+    pub last_index_duration: Option<Duration>,
 }
 
 pub struct IndexerActor;
 
-#[derive(Debug)]
+/// A scheduled [`IndexerActorMessage::AutoIndex`] tick.
+struct PendingTick {
+    interval: Duration,
+    handle: JoinHandle<Result<(), MessagingErr<IndexerActorMessage>>>,
+    /// Set right before the tick is sent, an arriving tick of the same interval that isn't
+    /// sent yet is a duplicate.
+    sent: Arc<AtomicBool>,
+}
+
 pub struct IndexerActorState {
+    /// Name of the repository, its directory name.
+    name: String,
     last_indexed: Option<Instant>,
     last_commit_hash: Option<String>,
     timer_interval: Option<Duration>,
-    git_service: GitService,
+    paused_interval: Option<Duration>,
+    /// The scheduled [`IndexerActorMessage::AutoIndex`] tick, if one is pending.
+    pending_tick: Option<PendingTick>,
+    /// Interval of a tick that was already sent when [`IndexerActorMessage::IndexNow`]
+    /// reset the schedule, it's ignored once it arrives.
+    stale_tick: Option<Duration>,
+    git_service: Box<dyn GitBackend>,
+    /// Cancelled once the actor stops.
+    cancellation_token: CancellationToken,
+    /// Branch of the remote to poll, its `HEAD` if unset.
+    branch: Option<String>,
+    state_path: PathBuf,
+    /// Deleted in `post_stop` if set, see [`IndexerActorArguments::with_cleanup_on_stop`].
+    cleanup_path: Option<PathBuf>,
+    subscribers: Vec<Sender<IndexerEvent>>,
+    broadcast: Option<broadcast::Sender<DiffBatch>>,
+    webhook: Option<Webhook>,
+    consecutive_failures: u32,
+    backoff_base: Duration,
+    backoff_max: Duration,
+    last_index_duration: Option<Duration>,
+    git_url: String,
+    clone_options: CloneOptions,
+    reclone_threshold: u32,
+    interval_jitter: f64,
+    /// Skip the diff across a history rewrite, see [`IndexerEvent::HistoryRewrite`].
+    rebaseline_on_rewrite: bool,
+    /// See [`IndexerActorArguments::with_dry_run`].
+    dry_run: bool,
+    /// Range of the last emitted batch, it isn't emitted again.
+    last_emitted: Option<EmittedRange>,
+    #[cfg(feature = "sqlite")]
+    store: Option<DiffStore>,
 }
 
+#[derive(Clone)]
 pub struct IndexerActorArguments {
     git_url: String,
     dir_name: Option<String>,
+    base_dir: Option<PathBuf>,
+    auth: AuthConfig,
+    clone_options: CloneOptions,
+    command_timeout: Duration,
+    diff_error_mode: DiffErrorMode,
+    path_filters: Vec<String>,
+    diff_algorithm: Option<DiffAlgorithm>,
+    git_binary: PathBuf,
+    retry_policy: RetryPolicy,
+    cancellation_token: CancellationToken,
+    command_permits: Option<Arc<Semaphore>>,
+    remote: String,
+    branch: Option<String>,
+    backoff_base: Duration,
+    backoff_max: Duration,
+    reclone_threshold: u32,
+    interval_jitter: f64,
+    webhook: Option<Webhook>,
+    #[cfg(feature = "sqlite")]
+    database_path: Option<PathBuf>,
+    backend_kind: GitBackendKind,
+    backend: Option<Arc<dyn GitBackend>>,
+    subscribers: Vec<Sender<IndexerEvent>>,
+    broadcast: Option<broadcast::Sender<DiffBatch>>,
+    cleanup_on_stop: bool,
+    rebaseline_on_rewrite: bool,
+    dry_run: bool,
 }
 
+pub const DEFAULT_BACKOFF_BASE: Duration = Duration::from_secs(30);
+pub const DEFAULT_BACKOFF_MAX: Duration = Duration::from_secs(30 * 60);
+pub const DEFAULT_RECLONE_THRESHOLD: u32 = 5;
+
 impl IndexerActorArguments {
     pub fn new(git_url: String, dir_name: Option<String>) -> Self {
-        Self { git_url, dir_name }
+        Self {
+            git_url,
+            dir_name,
+            base_dir: None,
+            auth: AuthConfig::None,
+            clone_options: CloneOptions::default(),
+            command_timeout: DEFAULT_COMMAND_TIMEOUT,
+            diff_error_mode: DiffErrorMode::default(),
+            path_filters: Vec::new(),
+            diff_algorithm: None,
+            git_binary: PathBuf::from(DEFAULT_GIT_BINARY),
+            retry_policy: RetryPolicy::default(),
+            cancellation_token: CancellationToken::new(),
+            command_permits: None,
+            remote: DEFAULT_REMOTE.to_string(),
+            branch: None,
+            backoff_base: DEFAULT_BACKOFF_BASE,
+            backoff_max: DEFAULT_BACKOFF_MAX,
+            reclone_threshold: DEFAULT_RECLONE_THRESHOLD,
+            interval_jitter: 0.0,
+            webhook: None,
+            #[cfg(feature = "sqlite")]
+            database_path: None,
+            backend_kind: GitBackendKind::default(),
+            backend: None,
+            subscribers: Vec::new(),
+            broadcast: None,
+            cleanup_on_stop: false,
+            rebaseline_on_rewrite: false,
+            dry_run: false,
+        }
+    }
+
+    /// Directory the repository is cloned into, derived from the url unless set explicitly.
+    pub fn dir_name(&self) -> String {
+        self.dir_name
+            .clone()
+            .unwrap_or_else(|| get_dir_name_from_url(&self.git_url))
+    }
+
+    /// Path the repository is cloned to, [`Self::dir_name`] inside the base directory.
+    pub fn repository_path(&self) -> PathBuf {
+        match &self.base_dir {
+            Some(base_dir) => base_dir.join(self.dir_name()),
+            None => PathBuf::from(self.dir_name()),
+        }
+    }
+
+    /// Clones into `base_dir` instead of the current working directory, it's created if
+    /// missing.
+    pub fn with_base_dir(mut self, base_dir: PathBuf) -> Self {
+        self.base_dir = Some(base_dir);
+        self
+    }
+
+    pub fn with_auth(mut self, auth: AuthConfig) -> Self {
+        self.auth = auth;
+        self
+    }
+
+    pub fn with_clone_options(mut self, clone_options: CloneOptions) -> Self {
+        self.clone_options = clone_options;
+        self
+    }
+
+    pub fn with_command_timeout(mut self, command_timeout: Duration) -> Self {
+        self.command_timeout = command_timeout;
+        self
+    }
+
+    pub fn with_diff_error_mode(mut self, diff_error_mode: DiffErrorMode) -> Self {
+        self.diff_error_mode = diff_error_mode;
+        self
+    }
+
+    /// Only indexes changes of files matching one of the git pathspecs.
+    pub fn with_path_filters(mut self, path_filters: Vec<String>) -> Self {
+        self.path_filters = path_filters;
+        self
+    }
+
+    /// Diffs with `diff_algorithm` instead of git's default.
+    pub fn with_diff_algorithm(mut self, diff_algorithm: DiffAlgorithm) -> Self {
+        self.diff_algorithm = Some(diff_algorithm);
+        self
+    }
+
+    /// Runs `git_binary` instead of `git` from `PATH`.
+    pub fn with_git_binary(mut self, git_binary: PathBuf) -> Self {
+        self.git_binary = git_binary;
+        self
+    }
+
+    /// Retries of clones and fetches that failed with a transient network error.
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Cancelling `cancellation_token` kills the actor's running git commands.
+    ///
+    /// A stopping actor first finishes the message it's handling, so cancel the token before
+    /// stopping to abort a long clone or fetch. Stopping the actor cancels its own child token.
+    pub fn with_cancellation_token(mut self, cancellation_token: CancellationToken) -> Self {
+        self.cancellation_token = cancellation_token;
+        self
+    }
+
+    /// Limits concurrent git commands to the permits of the semaphore, see
+    /// [`GitService::with_command_permits`].
+    pub fn with_command_permits(mut self, command_permits: Arc<Semaphore>) -> Self {
+        self.command_permits = Some(command_permits);
+        self
+    }
+
+    /// Fetches `remote` instead of `origin`.
+    pub fn with_remote(mut self, remote: String) -> Self {
+        self.remote = remote;
+        self
+    }
+
+    /// Polls only `branch` of the remote instead of its `HEAD`.
+    pub fn with_branch(mut self, branch: String) -> Self {
+        self.branch = Some(branch);
+        self
+    }
+
+    /// Delay before the first retry after a failed auto-index, doubled on every further
+    /// failure up to `max`. A retry never comes sooner than the auto-index interval.
+    pub fn with_backoff(mut self, base: Duration, max: Duration) -> Self {
+        self.backoff_base = base;
+        self.backoff_max = max;
+        self
+    }
+
+    /// Verifies the repository after every `threshold` consecutive failed index runs and
+    /// clones it again if it's corrupted, `0` disables it.
+    pub fn with_reclone_threshold(mut self, threshold: u32) -> Self {
+        self.reclone_threshold = threshold;
+        self
+    }
+
+    /// Randomly shifts every auto-index delay by up to `fraction` of it in either direction,
+    /// so repositories started together don't fetch in lockstep. Clamped to `0.0..=1.0`.
+    pub fn with_interval_jitter(mut self, fraction: f64) -> Self {
+        self.interval_jitter = fraction.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Posts the actions of every index cycle with changes to `url`, signed with `secret`
+    /// if set, see [`Webhook`].
+    pub fn with_webhook(mut self, url: String, secret: Option<String>) -> Self {
+        self.webhook = Some(Webhook::new(url, secret));
+        self
+    }
+
+    /// Clones and polls the repository with `backend_kind`, the `git` CLI by default.
+    ///
+    /// `git_binary` and the retry policy only apply to [`GitBackendKind::Cli`].
+    pub fn with_backend_kind(mut self, backend_kind: GitBackendKind) -> Self {
+        self.backend_kind = backend_kind;
+        self
+    }
+
+    /// Uses `backend` instead of cloning the repository and running git.
+    pub fn with_backend(mut self, backend: Arc<dyn GitBackend>) -> Self {
+        self.backend = Some(backend);
+        self
+    }
+
+    /// Subscribes `subscriber` before the actor starts, unlike [`IndexerActorMessage::Subscribe`]
+    /// it also receives [`IndexerEvent::Cloned`].
+    pub fn with_subscriber(mut self, subscriber: Sender<IndexerEvent>) -> Self {
+        self.subscribers.push(subscriber);
+        self
+    }
+
+    /// Publishes every [`DiffBatch`] to `broadcast`, which can be shared between actors,
+    /// e.g. for [`crate::server`].
+    pub fn with_broadcast(mut self, broadcast: broadcast::Sender<DiffBatch>) -> Self {
+        self.broadcast = Some(broadcast);
+        self
+    }
+
+    /// Deletes the clone and its poll state once the actor stops, e.g. for ephemeral CI runs.
+    /// Off by default so restarts reuse the clone.
+    pub fn with_cleanup_on_stop(mut self, cleanup_on_stop: bool) -> Self {
+        self.cleanup_on_stop = cleanup_on_stop;
+        self
+    }
+
+    /// Continues from the new commit without diffing once the remote's history was
+    /// rewritten, instead of emitting the diff between the unrelated commits.
+    pub fn with_rebaseline_on_rewrite(mut self, rebaseline_on_rewrite: bool) -> Self {
+        self.rebaseline_on_rewrite = rebaseline_on_rewrite;
+        self
+    }
+
+    /// Fetches, diffs and notifies subscribers as usual, but neither saves the poll state,
+    /// records to the database nor calls the webhook, to preview what would be indexed.
+    pub fn with_dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Records every detected [`DiffAction`] in the SQLite database at `path`.
+    #[cfg(feature = "sqlite")]
+    pub fn with_database(mut self, path: PathBuf) -> Self {
+        self.database_path = Some(path);
+        self
     }
 }
 
@@ -44,141 +442,2355 @@ async fn dir_exists<P: AsRef<Path>>(path: P) -> bool {
     }
 }
 
-fn get_dir_name_from_url(git_url: &str) -> &str {
-    git_url
-        .rsplit('/')
-        .next()
-        .and_then(|s| s.strip_suffix(".git"))
-        .unwrap_or(git_url)
-}
+/// Whether the directory at `path` is what an interrupted clone leaves behind, it's empty
+/// or has the `HEAD` and `objects` of a git dir. Anything else may be the user's and isn't
+/// deleted to clone again.
+async fn is_interrupted_clone(path: &Path) -> Result<bool, std::io::Error> {
+    if tokio::fs::read_dir(path)
+        .await?
+        .next_entry()
+        .await?
+        .is_none()
+    {
+        return Ok(true);
+    }
 
-#[async_trait::async_trait]
-impl Actor for IndexerActor {
-    type State = IndexerActorState;
-    type Msg = IndexerActorMessage;
-    type Arguments = IndexerActorArguments;
+    for git_dir in [path.to_path_buf(), path.join(".git")] {
+        let has_head = tokio::fs::metadata(git_dir.join("HEAD"))
+            .await
+            .is_ok_and(|meta| meta.is_file());
+        if has_head && dir_exists(git_dir.join("objects")).await {
+            return Ok(true);
+        }
+    }
 
-    async fn pre_start(
-        &self,
-        _myself: ActorRef<Self::Msg>,
-        arguments: Self::Arguments,
-    ) -> Result<Self::State, ActorProcessingErr> {
-        let dir_name = arguments
-            .dir_name
-            .unwrap_or_else(|| get_dir_name_from_url(&arguments.git_url).to_string());
+    Ok(false)
+}
 
-        let git_service = GitService::new(PathBuf::from(&dir_name));
+/// Delay before the next auto-index after `failures` consecutive failed runs.
+fn backoff_delay(failures: u32, base: Duration, max: Duration) -> Duration {
+    let factor = 2u32.saturating_pow(failures.saturating_sub(1));
 
-        let last_commit_hash = if !dir_exists(&dir_name).await {
-            log::info!(
-                "Cloning repository from {} into {}",
-                arguments.git_url,
-                dir_name
-            );
+    base.saturating_mul(factor).min(max)
+}
+
+/// Delay until the next auto-index every `interval`, backing off after `failures`
+/// consecutive failed runs but never retrying sooner than the interval.
+fn auto_index_delay(interval: Duration, failures: u32, base: Duration, max: Duration) -> Duration {
+    if failures == 0 {
+        interval
+    } else {
+        backoff_delay(failures, base, max).max(interval)
+    }
+}
 
+/// Latest commit of `branch` of `remote`, or of `FETCH_HEAD` without a branch.
+async fn current_commit_hash(
+    git_service: &dyn GitBackend,
+    remote: &str,
+    branch: Option<&str>,
+) -> Result<Option<String>, GitError> {
+    match branch {
+        Some(branch) => {
             git_service
-                .clone_repository(&arguments.git_url)
+                .get_current_commit_hash_from_rev(&format!("{}/{}", remote, branch))
                 .await
-                .map_err(|e| format!("Failed to clone repository: {:?}", e))?;
+        }
+        None => git_service.get_current_commit_hash_from_fetch_head().await,
+    }
+}
 
-            None
-        } else {
-            log::info!("Repository already cloned in {}, skipping", &dir_name);
+/// Commit the first index diffs against without a saved hash, the last fetched commit or,
+/// before the first fetch, the one the clone was made at.
+async fn baseline_commit_hash(
+    git_service: &dyn GitBackend,
+    remote: &str,
+    branch: Option<&str>,
+) -> Result<Option<String>, GitError> {
+    // a working tree clone already has the remote branches
+    if let Some(hash) = current_commit_hash(git_service, remote, branch).await? {
+        return Ok(Some(hash));
+    }
 
-            git_service
-                .get_current_commit_hash_from_fetch_head()
-                .await
-                .map_err(|e| format!("Failed to get commit hash: {:?}", e))?
+    // a bare clone has the remote's branches as local branches, at the cloned commit
+    git_service
+        .get_current_commit_hash_from_rev(branch.unwrap_or("HEAD"))
+        .await
+}
+
+/// What an index cycle polls, shared by [`IndexerActor`] and [`index_once`].
+struct Poller<'a> {
+    git_service: &'a dyn GitBackend,
+    /// Name of the repository, the metrics are labeled with it.
+    repository: &'a str,
+    branch: Option<&'a str>,
+    rebaseline_on_rewrite: bool,
+}
+
+/// Result of [`Poller::index`].
+#[derive(Debug, Default)]
+struct IndexCycle {
+    /// Latest commit of the polled branch, `None` for an empty repository.
+    current_commit: Option<String>,
+    /// The previous commit is no ancestor of `current_commit`.
+    rewritten: bool,
+    /// Actions between the previous and the current commit, empty if they weren't diffed.
+    actions: Vec<DiffAction>,
+}
+
+impl Poller<'_> {
+    /// Fetches the polled branch and diffs it against `previous`, the commit indexed last.
+    ///
+    /// The range of `last_emitted` isn't diffed again. Neither emits nor saves anything,
+    /// that's up to the caller.
+    async fn index(
+        &self,
+        previous: Option<&str>,
+        last_emitted: Option<&EmittedRange>,
+    ) -> Result<IndexCycle, GitError> {
+        // pull latest changes from remote
+        let fetched = match self.branch {
+            Some(branch) => self.git_service.fetch_branch(branch).await,
+            None => self.git_service.fetch().await,
+        };
+        // latest commit hash
+        let current_commit = match fetched {
+            Ok(current_commit) => current_commit,
+            Err(e) => {
+                metrics::counter!("indexer_fetch_failures_total", "repository" => self.repository.to_string())
+                    .increment(1);
+                return Err(e);
+            }
+        };
+        let mut cycle = IndexCycle {
+            current_commit: current_commit.clone(),
+            ..IndexCycle::default()
         };
 
-        Ok(IndexerActorState {
-            last_indexed: None,
-            last_commit_hash,
-            timer_interval: None,
-            git_service,
-        })
+        // a force push leaves the old commit out of the new history
+        if let (Some(old_commit), Some(current_commit)) = (previous, &current_commit)
+            && old_commit != current_commit
+            && !self
+                .git_service
+                .is_ancestor(old_commit, current_commit)
+                .await?
+        {
+            log::warn!(
+                "History was rewritten, {} is no ancestor of {}",
+                old_commit,
+                current_commit
+            );
+            metrics::counter!("indexer_history_rewrites_total", "repository" => self.repository.to_string())
+                .increment(1);
+            cycle.rewritten = true;
+        }
+
+        match (previous, current_commit.as_deref()) {
+            (None, None) => {
+                log::info!("No commits found in repository.");
+            }
+            (None, Some(current_commit)) => {
+                log::info!("Initial commit hash: {}", current_commit);
+            }
+            (Some(old_commit), None) => {
+                log::error!(
+                    "Previously had commit hash {}, but now no commits found!",
+                    old_commit
+                );
+            }
+            (Some(old_commit), Some(current_commit))
+                if last_emitted.is_some_and(|range| range.is(Some(old_commit), current_commit)) =>
+            {
+                log::info!(
+                    "Commits {}..{} were already emitted, not emitting them again.",
+                    old_commit,
+                    current_commit
+                );
+            }
+            (Some(_), Some(current_commit)) if cycle.rewritten && self.rebaseline_on_rewrite => {
+                log::info!("Continuing from {} without diffing", current_commit);
+            }
+            // diff with the previous commit
+            (Some(old_commit), Some(current_commit)) if old_commit != current_commit => {
+                cycle.actions = self.diff(old_commit, current_commit).await?;
+            }
+            (Some(_), Some(_)) => {
+                log::info!("No new commits to index.");
+            }
+        }
+
+        Ok(cycle)
     }
 
-    async fn handle(
+    /// Diffs two commits, logging the new commit and the size of the diff.
+    async fn diff(
         &self,
-        myself: ActorRef<Self::Msg>,
-        message: Self::Msg,
-        state: &mut Self::State,
-    ) -> Result<(), ActorProcessingErr> {
-        log::info!("Handling message: '{:?}'", message);
+        old_commit: &str,
+        current_commit: &str,
+    ) -> Result<Vec<DiffAction>, GitError> {
+        log::debug!("Diffing commits {} -> {}", old_commit, current_commit);
 
-        match message {
-            IndexerActorMessage::Index => {
-                state.last_indexed = Some(Instant::now());
+        match self.git_service.get_commit_metadata(current_commit).await {
+            Ok(meta) => log::info!(
+                "New commit {} by {} <{}> at {:?}: {}",
+                meta.hash,
+                meta.author_name,
+                meta.author_email,
+                meta.committed_at,
+                meta.subject
+            ),
+            Err(e) => log::warn!("Failed to read metadata of {}: {:?}", current_commit, e),
+        }
 
-                // pull latest changes from remote
-                state.git_service.fetch().await.unwrap();
+        let patches = self
+            .git_service
+            .diff_commits(old_commit, current_commit)
+            .await?;
 
-                // latest commit hash
-                let current_commit_hash = state
-                    .git_service
-                    .get_current_commit_hash_from_fetch_head()
-                    .await
-                    .unwrap();
-                match (&state.last_commit_hash, &current_commit_hash) {
-                    (None, None) => {
-                        log::info!("No commits found in repository.");
-                    }
-                    (None, Some(current_commit)) => {
-                        log::info!("Initial commit hash: {}", current_commit);
-                    }
-                    (Some(old_commit), None) => {
-                        log::error!(
-                            "Previously had commit hash {}, but now no commits found!",
-                            old_commit
-                        );
-                    }
-                    // diff with last_commit_hash
-                    (Some(old_commit), Some(current_commit)) if old_commit != current_commit => {
-                        log::debug!("Diffing commits {} -> {}", old_commit, current_commit);
-
-                        let patches = state
-                            .git_service
-                            .diff_commits(&old_commit, &current_commit)
-                            .await
-                            .unwrap();
-
-                        for patch in patches {
-                            // TODO store in database or do something with it
-                            log::debug!("Patch: {:?}", patch);
-                        }
-                    }
-                    (Some(_), Some(_)) => {
-                        log::info!("No new commits to index.");
-                    }
-                }
+        for patch in &patches {
+            log::debug!("Patch: {:?}", patch);
+        }
 
-                state.last_commit_hash = current_commit_hash;
+        match self
+            .git_service
+            .diff_stats(old_commit, current_commit)
+            .await
+        {
+            Ok(stats) => {
+                let added: u64 = stats.iter().filter_map(|stat| stat.added).sum();
+                let deleted: u64 = stats.iter().filter_map(|stat| stat.deleted).sum();
+                log::info!(
+                    "{} files changed, {} insertions, {} deletions",
+                    stats.len(),
+                    added,
+                    deleted
+                );
+
+                metrics::counter!("indexer_lines_added_total", "repository" => self.repository.to_string())
+                    .increment(added);
+                metrics::counter!("indexer_lines_deleted_total", "repository" => self.repository.to_string())
+                    .increment(deleted);
             }
-            IndexerActorMessage::AutoIndex(duration) => {
-                // check if the auto index originated from the current interval
-                if let Some(interval) = state.timer_interval
-                    && duration == interval
+            Err(e) => log::warn!("Failed to read diff stats: {:?}", e),
+        }
+
+        metrics::counter!("indexer_cycles_with_changes_total", "repository" => self.repository.to_string())
+            .increment(1);
+        metrics::histogram!("indexer_diff_actions", "repository" => self.repository.to_string())
+            .record(patches.len() as f64);
+
+        Ok(patches.into_iter().collect())
+    }
+}
+
+/// Builds the backend for `arguments` and clones the repository unless it already is one.
+///
+/// Returns whether it was cloned, an injected backend is never cloned.
+async fn open_repository(
+    arguments: &IndexerActorArguments,
+    cancellation_token: CancellationToken,
+) -> Result<(Box<dyn GitBackend>, bool), GitError> {
+    if let Some(backend) = &arguments.backend {
+        return Ok((Box::new(backend.clone()), false));
+    }
+
+    let repository_path = arguments.repository_path();
+    if let Some(base_dir) = &arguments.base_dir {
+        tokio::fs::create_dir_all(base_dir).await?;
+    }
+
+    let git_service: Box<dyn GitBackend> = match arguments.backend_kind {
+        GitBackendKind::Cli => {
+            let mut git_service =
+                GitService::with_auth(repository_path.clone(), arguments.auth.clone())
+                    .with_command_timeout(arguments.command_timeout)
+                    .with_diff_error_mode(arguments.diff_error_mode)
+                    .with_path_filters(arguments.path_filters.clone())
+                    .with_git_binary(arguments.git_binary.clone())
+                    .with_remote(arguments.remote.clone())
+                    .with_retry_policy(arguments.retry_policy)
+                    .with_cancellation_token(cancellation_token);
+            if let Some(command_permits) = &arguments.command_permits {
+                git_service = git_service.with_command_permits(command_permits.clone());
+            }
+            if let Some(diff_algorithm) = arguments.diff_algorithm {
+                git_service = git_service.with_diff_algorithm(diff_algorithm);
+            }
+
+            Box::new(git_service)
+        }
+        #[cfg(feature = "libgit2")]
+        GitBackendKind::Libgit2 => {
+            let mut git_service =
+                Git2Backend::with_auth(repository_path.clone(), arguments.auth.clone())
+                    .with_command_timeout(arguments.command_timeout)
+                    .with_diff_error_mode(arguments.diff_error_mode)
+                    .with_path_filters(arguments.path_filters.clone())
+                    .with_remote(arguments.remote.clone())
+                    .with_cancellation_token(cancellation_token);
+            if let Some(command_permits) = &arguments.command_permits {
+                git_service = git_service.with_command_permits(command_permits.clone());
+            }
+            if let Some(diff_algorithm) = arguments.diff_algorithm {
+                git_service = git_service.with_diff_algorithm(diff_algorithm);
+            }
+
+            Box::new(git_service)
+        }
+    };
+
+    let clone = if !dir_exists(&repository_path).await {
+        log::info!(
+            "Cloning repository from {} into {}",
+            arguments.git_url,
+            repository_path.display()
+        );
+
+        true
+    } else if !git_service.is_repository().await? {
+        if !is_interrupted_clone(&repository_path).await? {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!(
+                    "{} is neither a git repository nor an interrupted clone, not deleting it",
+                    repository_path.display()
+                ),
+            )
+            .into());
+        }
+
+        log::warn!(
+            "{} is an interrupted clone, cloning {} again",
+            repository_path.display(),
+            arguments.git_url
+        );
+
+        true
+    } else {
+        log::info!(
+            "Repository already cloned in {}, skipping",
+            repository_path.display()
+        );
+
+        false
+    };
+
+    if clone {
+        // deletes what's left of an interrupted clone first
+        git_service
+            .reclone(&arguments.git_url, &arguments.clone_options)
+            .await?;
+    }
+
+    Ok((git_service, clone))
+}
+
+/// Runs a single index cycle without an actor and returns the detected actions.
+///
+/// Clones the repository if needed and diffs the polled branch against `baseline`, or the
+/// saved commit hash without one, like an [`IndexerActor`] would. The new commit is saved
+/// for the next call unless it's a dry run. Subscribers, the webhook and the database
+/// aren't involved.
+pub async fn index_once(
+    arguments: &IndexerActorArguments,
+    baseline: Option<&str>,
+) -> Result<Vec<DiffAction>, GitError> {
+    let repository_path = arguments.repository_path();
+    let state_path = PollState::path_for(&repository_path);
+    let (git_service, _) =
+        open_repository(arguments, arguments.cancellation_token.child_token()).await?;
+    let branch = arguments.branch.as_deref();
+
+    let mut poll_state = PollState::load(&state_path).await.unwrap_or_default();
+    let baseline = match baseline {
+        Some(baseline) => Some(baseline.to_string()),
+        None => match &poll_state.last_commit_hash {
+            Some(hash) => Some(hash.clone()),
+            None => baseline_commit_hash(git_service.as_ref(), &arguments.remote, branch).await?,
+        },
+    };
+
+    let poller = Poller {
+        git_service: git_service.as_ref(),
+        repository: &arguments.dir_name(),
+        branch,
+        rebaseline_on_rewrite: arguments.rebaseline_on_rewrite,
+    };
+    let cycle = poller
+        .index(baseline.as_deref(), poll_state.last_emitted.as_ref())
+        .await?;
+
+    if !arguments.dry_run {
+        if let Some(current_commit) = &cycle.current_commit
+            && !cycle.actions.is_empty()
+        {
+            poll_state.last_emitted = Some(EmittedRange::new(baseline.as_deref(), current_commit));
+        }
+        poll_state.last_commit_hash = cycle.current_commit.or(baseline);
+        poll_state.save(&state_path).await?;
+    }
+
+    Ok(cycle.actions)
+}
+
+/// `delay` shifted by a random amount of up to `fraction` of it in either direction.
+fn jittered(delay: Duration, fraction: f64) -> Duration {
+    if fraction <= 0.0 {
+        return delay;
+    }
+
+    delay.mul_f64(1.0 + rand::random_range(-fraction..=fraction))
+}
+
+/// Directory name derived from the url, e.g. `crates.io-index-1a2b3c4d`.
+///
+/// The last path segment of the url without its `.git` suffix, with characters that
+/// aren't safe in a directory name replaced, followed by a hash of the whole url, so
+/// `github.com/a/index.git` and `gitlab.com/b/index.git` don't share a directory.
+fn get_dir_name_from_url(git_url: &str) -> String {
+    let segment = git_url
+        .trim_end_matches('/')
+        .rsplit(['/', ':'])
+        .next()
+        .map(|s| s.strip_suffix(".git").unwrap_or(s))
+        .unwrap_or_default();
+
+    let sanitized: String = segment
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let sanitized = sanitized.trim_start_matches('.');
+    let sanitized = if sanitized.is_empty() {
+        "repository"
+    } else {
+        sanitized
+    };
+
+    // sha256 instead of `DefaultHasher`, the name has to stay the same across builds
+    let digest = Sha256::digest(git_url.as_bytes());
+    let hash: String = digest[..4].iter().map(|b| format!("{:02x}", b)).collect();
+
+    format!("{}-{}", sanitized, hash)
+}
+
+impl IndexerActorState {
+    fn status(&self) -> IndexerStatus {
+        IndexerStatus {
+            last_indexed: self.last_indexed,
+            last_commit_hash: self.last_commit_hash.clone(),
+            auto_indexing: self.timer_interval.is_some(),
+            timer_interval: self.timer_interval,
+            paused_interval: self.paused_interval,
+            consecutive_failures: self.consecutive_failures,
+            last_index_duration: self.last_index_duration,
+        }
+    }
+
+    /// Runs an index cycle and keeps track of consecutive failures.
+    async fn run_index(&mut self) {
+        let started = Instant::now();
+        let result = self.index().await;
+        let elapsed = started.elapsed();
+        self.last_index_duration = Some(elapsed);
+
+        log::debug!("Index run took {:?}", elapsed);
+
+        let repository = self.name.clone();
+        metrics::counter!("indexer_cycles_total", "repository" => repository.clone()).increment(1);
+        metrics::gauge!("indexer_last_index_duration_seconds", "repository" => repository)
+            .set(elapsed.as_secs_f64());
+
+        match result {
+            Ok(()) => self.consecutive_failures = 0,
+            Err(e) => {
+                self.consecutive_failures += 1;
+                log::error!(
+                    "Indexing {} failed ({} in a row), retrying on the next run: {:?}",
+                    self.name,
+                    self.consecutive_failures,
+                    e
+                );
+
+                if self.reclone_threshold > 0
+                    && self
+                        .consecutive_failures
+                        .is_multiple_of(self.reclone_threshold)
                 {
-                    myself.cast(IndexerActorMessage::Index)?;
+                    self.recover().await;
+                }
+            }
+        }
+    }
 
-                    // schedule next auto-index
-                    myself.send_after(interval, move || IndexerActorMessage::AutoIndex(duration));
-                } else {
-                    log::info!("Auto-indexing interval changed or stopped, not indexing.");
+    /// Clones the repository again if it doesn't pass verification.
+    async fn recover(&mut self) {
+        match self.git_service.verify().await {
+            Ok(true) => log::info!("Repository {} is intact, not recloning", self.name),
+            Ok(false) => {
+                log::warn!("Repository {} is corrupted, cloning it again", self.name);
+
+                match self
+                    .git_service
+                    .reclone(&self.git_url, &self.clone_options)
+                    .await
+                {
+                    Ok(()) => log::info!("Cloned {} again", self.name),
+                    Err(e) => log::error!("Failed to clone {} again: {:?}", self.name, e),
                 }
             }
-            IndexerActorMessage::StartAutoIndex(duration) => {
-                log::info!("Starting auto-indexing every {:?}.", duration);
-                state.timer_interval = Some(duration);
-                myself.send_after(duration, move || IndexerActorMessage::AutoIndex(duration));
+            Err(e) => log::error!("Failed to verify repository {}: {:?}", self.name, e),
+        }
+    }
+
+    /// Schedules the first tick of auto-indexing every `interval`.
+    ///
+    /// A tick of the same interval that is still pending, e.g. when resuming right after
+    /// pausing, continues the chain instead, a second chain would index twice as often.
+    fn schedule_auto_index(&mut self, myself: &ActorRef<IndexerActorMessage>, interval: Duration) {
+        if self
+            .pending_tick
+            .as_ref()
+            .is_some_and(|tick| tick.interval == interval)
+        {
+            return;
+        }
+
+        self.schedule_tick(myself, interval, interval);
+    }
+
+    /// Sends the next tick of auto-indexing every `interval` after `delay`.
+    ///
+    /// The message keeps the base interval, only the delay is jittered.
+    fn schedule_tick(
+        &mut self,
+        myself: &ActorRef<IndexerActorMessage>,
+        interval: Duration,
+        delay: Duration,
+    ) {
+        let delay = jittered(delay, self.interval_jitter);
+        let sent = Arc::new(AtomicBool::new(false));
+        let handle = myself.send_after(delay, {
+            let sent = sent.clone();
+            move || {
+                sent.store(true, Ordering::Release);
+                IndexerActorMessage::AutoIndex(interval)
             }
-            IndexerActorMessage::StopAutoIndex => {
-                log::info!("Stopping auto-indexing.");
-                state.timer_interval = None;
+        });
+        self.pending_tick = Some(PendingTick {
+            interval,
+            handle,
+            sent,
+        });
+    }
+
+    /// Drops the pending tick, so a new one can be scheduled from now.
+    async fn cancel_pending_tick(&mut self) {
+        let Some(tick) = self.pending_tick.take() else {
+            return;
+        };
+
+        tick.handle.abort();
+        match tick.handle.await {
+            Err(e) if e.is_cancelled() => {}
+            // the tick was sent before it could be cancelled and waits in the mailbox
+            _ => self.stale_tick = Some(tick.interval),
+        }
+    }
+
+    /// Delay until the next auto-index, backing off while indexing keeps failing.
+    fn next_auto_index_delay(&self, interval: Duration) -> Duration {
+        auto_index_delay(
+            interval,
+            self.consecutive_failures,
+            self.backoff_base,
+            self.backoff_max,
+        )
+    }
+
+    /// Records `actions` found between `old_commit` and `commit` and sends them to the
+    /// subscribers and the webhook.
+    async fn emit(
+        &mut self,
+        old_commit: Option<&str>,
+        commit: &str,
+        actions: Vec<DiffAction>,
+    ) -> Result<(), IndexError> {
+        if actions.is_empty() {
+            return Ok(());
+        }
+        log::debug!("Emitting {} actions of {}", actions.len(), commit);
+        if self.dry_run {
+            for action in &actions {
+                log::info!("Dry run: {:?}", action);
             }
         }
 
-        Ok(())
+        let batch = DiffBatch {
+            repository: self.name.clone(),
+            old_commit: old_commit.map(str::to_string),
+            new_commit: commit.to_string(),
+            actions,
+        };
+
+        #[cfg(feature = "sqlite")]
+        if let Some(store) = &self.store {
+            store.insert_batch(batch.clone()).await?;
+        }
+
+        if let Some(webhook) = &self.webhook {
+            let webhook = webhook.clone();
+            let batch = batch.clone();
+            // delivery retries for a while, don't hold up the actor meanwhile
+            tokio::spawn(async move { webhook.send(&batch).await });
+        }
+
+        if let Some(broadcast) = &self.broadcast {
+            // fails only while nobody is listening
+            let _ = broadcast.send(batch.clone());
+        }
+
+        notify_subscribers(&mut self.subscribers, IndexerEvent::Changes(batch));
+        self.last_emitted = Some(EmittedRange::new(old_commit, commit));
+
+        Ok(())
+    }
+
+    /// Saves the poll state, unless it's a dry run. A failure is only logged.
+    async fn save_state(&self) {
+        if self.dry_run {
+            return;
+        }
+
+        let poll_state = PollState {
+            last_commit_hash: self.last_commit_hash.clone(),
+            last_emitted: self.last_emitted.clone(),
+        };
+        if let Err(e) = poll_state.save(&self.state_path).await {
+            log::error!(
+                "Failed to save state to {}: {}",
+                self.state_path.display(),
+                e
+            );
+        }
+    }
+
+    /// Diffs `from` against `to` and emits the actions like an index cycle would, without
+    /// moving `last_commit_hash`.
+    async fn index_range(&mut self, from: &str, to: &str) -> Result<(), IndexError> {
+        let mut commits = Vec::with_capacity(2);
+        for rev in [from, to] {
+            match self
+                .git_service
+                .get_current_commit_hash_from_rev(rev)
+                .await?
+            {
+                Some(commit) => commits.push(commit),
+                None => return Err(IndexError::UnknownRevision(rev.to_string())),
+            }
+        }
+        let (from, to) = (&commits[0], &commits[1]);
+
+        if self
+            .last_emitted
+            .as_ref()
+            .is_some_and(|range| range.is(Some(from), to))
+        {
+            log::info!("Range {}..{} was already emitted, skipping it", from, to);
+            return Ok(());
+        }
+
+        log::info!("Indexing range {} -> {}", from, to);
+        let actions = self.git_service.diff_commits(from, to).await?;
+        log::info!("Found {} actions in range", actions.len());
+
+        self.emit(Some(from), to, actions.into_iter().collect())
+            .await?;
+        self.save_state().await;
+
+        Ok(())
+    }
+
+    /// Runs a single index cycle.
+    ///
+    /// `last_commit_hash` is only advanced once the whole cycle succeeded, so a failed
+    /// cycle is retried from the same commit next time.
+    #[instrument(
+        skip(self),
+        fields(
+            repository = %self.name,
+            old_commit = self.last_commit_hash.as_deref(),
+            new_commit,
+        )
+    )]
+    async fn index(&mut self) -> Result<(), IndexError> {
+        self.last_indexed = Some(Instant::now());
+
+        let poller = Poller {
+            git_service: self.git_service.as_ref(),
+            repository: &self.name,
+            branch: self.branch.as_deref(),
+            rebaseline_on_rewrite: self.rebaseline_on_rewrite,
+        };
+        let cycle = poller
+            .index(self.last_commit_hash.as_deref(), self.last_emitted.as_ref())
+            .await?;
+        let current_commit_hash = cycle.current_commit;
+        Span::current().record("new_commit", current_commit_hash.as_deref());
+
+        match (&self.last_commit_hash, &current_commit_hash) {
+            (None, Some(current_commit)) => notify_subscribers(
+                &mut self.subscribers,
+                IndexerEvent::Ready {
+                    repo: self.name.clone(),
+                    head: current_commit.clone(),
+                },
+            ),
+            (Some(old_commit), Some(current_commit)) => {
+                if cycle.rewritten {
+                    notify_subscribers(
+                        &mut self.subscribers,
+                        IndexerEvent::HistoryRewrite {
+                            repo: self.name.clone(),
+                            old_commit: old_commit.clone(),
+                            new_commit: current_commit.clone(),
+                        },
+                    );
+                }
+
+                let old_commit = old_commit.clone();
+                self.emit(Some(&old_commit), current_commit, cycle.actions)
+                    .await?;
+            }
+            _ => {}
+        }
+
+        self.last_commit_hash = current_commit_hash;
+        self.save_state().await;
+
+        Ok(())
+    }
+}
+
+/// Sends `event` to every subscriber, dropping subscribers whose receiver is gone.
+///
+/// A subscriber that doesn't keep up misses the event instead of blocking the actor.
+fn notify_subscribers(subscribers: &mut Vec<Sender<IndexerEvent>>, event: IndexerEvent) {
+    subscribers.retain(|subscriber| match subscriber.try_send(event.clone()) {
+        Ok(()) => true,
+        Err(TrySendError::Full(_)) => {
+            log::warn!("Subscriber channel is full, dropping {:?}", event);
+            true
+        }
+        Err(TrySendError::Closed(_)) => {
+            log::info!("Subscriber closed its channel, removing it");
+            false
+        }
+    });
+}
+
+#[async_trait::async_trait]
+impl Actor for IndexerActor {
+    type State = IndexerActorState;
+    type Msg = IndexerActorMessage;
+    type Arguments = IndexerActorArguments;
+
+    async fn pre_start(
+        &self,
+        _myself: ActorRef<Self::Msg>,
+        arguments: Self::Arguments,
+    ) -> Result<Self::State, ActorProcessingErr> {
+        let dir_name = arguments.dir_name();
+        let repository_path = arguments.repository_path();
+
+        let state_path = PollState::path_for(&repository_path);
+        let cancellation_token = arguments.cancellation_token.child_token();
+
+        let (git_service, cloned) = open_repository(&arguments, cancellation_token.clone())
+            .await
+            .map_err(|e| {
+                format!(
+                    "Failed to open repository {}: {:?}",
+                    repository_path.display(),
+                    e
+                )
+            })?;
+
+        let poll_state = PollState::load(&state_path).await.unwrap_or_default();
+        let saved_commit_hash = poll_state.last_commit_hash;
+
+        let cloned_head = if cloned {
+            baseline_commit_hash(
+                git_service.as_ref(),
+                &arguments.remote,
+                arguments.branch.as_deref(),
+            )
+            .await
+            .map_err(|e| format!("Failed to get commit hash: {:?}", e))?
+        } else {
+            None
+        };
+
+        let last_commit_hash = if let Some(hash) = saved_commit_hash {
+            log::info!("Resuming from saved commit hash {}", hash);
+
+            Some(hash)
+        } else if cloned {
+            // the first index diffs against the cloned state
+            cloned_head.clone()
+        } else {
+            let hash = baseline_commit_hash(
+                git_service.as_ref(),
+                &arguments.remote,
+                arguments.branch.as_deref(),
+            )
+            .await
+            .map_err(|e| format!("Failed to get commit hash: {:?}", e))?;
+            if hash.is_none() {
+                // an empty repository, the first index with commits sets the baseline
+                log::info!("Nothing fetched yet in {}", repository_path.display());
+            }
+
+            hash
+        };
+
+        if arguments.dry_run {
+            log::info!("Dry run, {} is neither recorded nor saved", dir_name);
+        }
+
+        #[cfg(feature = "sqlite")]
+        let store = match &arguments.database_path {
+            Some(_) if arguments.dry_run => None,
+            Some(path) => Some(
+                DiffStore::open(path)
+                    .await
+                    .map_err(|e| format!("Failed to open database: {:?}", e))?,
+            ),
+            None => None,
+        };
+
+        let mut state = IndexerActorState {
+            name: dir_name,
+            last_indexed: None,
+            last_commit_hash,
+            timer_interval: None,
+            paused_interval: None,
+            pending_tick: None,
+            stale_tick: None,
+            git_service,
+            cancellation_token,
+            branch: arguments.branch,
+            state_path,
+            cleanup_path: arguments.cleanup_on_stop.then(|| repository_path.clone()),
+            subscribers: arguments.subscribers,
+            broadcast: arguments.broadcast,
+            webhook: arguments.webhook.filter(|_| !arguments.dry_run),
+            consecutive_failures: 0,
+            backoff_base: arguments.backoff_base,
+            backoff_max: arguments.backoff_max,
+            last_index_duration: None,
+            git_url: arguments.git_url,
+            clone_options: arguments.clone_options,
+            reclone_threshold: arguments.reclone_threshold,
+            interval_jitter: arguments.interval_jitter,
+            rebaseline_on_rewrite: arguments.rebaseline_on_rewrite,
+            dry_run: arguments.dry_run,
+            last_emitted: poll_state.last_emitted,
+            #[cfg(feature = "sqlite")]
+            store,
+        };
+
+        if cloned {
+            log::info!("Cloned {} at {:?}", state.name, cloned_head);
+            notify_subscribers(
+                &mut state.subscribers,
+                IndexerEvent::Cloned {
+                    repo: state.name.clone(),
+                    head: cloned_head,
+                },
+            );
+        }
+        if let Some(head) = &state.last_commit_hash {
+            notify_subscribers(
+                &mut state.subscribers,
+                IndexerEvent::Ready {
+                    repo: state.name.clone(),
+                    head: head.clone(),
+                },
+            );
+        }
+
+        Ok(state)
+    }
+
+    async fn post_stop(
+        &self,
+        _myself: ActorRef<Self::Msg>,
+        state: &mut Self::State,
+    ) -> Result<(), ActorProcessingErr> {
+        state.cancellation_token.cancel();
+
+        if let Some(path) = &state.cleanup_path {
+            log::info!("Removing {}", path.display());
+
+            let results = [
+                (path, tokio::fs::remove_dir_all(path).await),
+                (
+                    &state.state_path,
+                    tokio::fs::remove_file(&state.state_path).await,
+                ),
+            ];
+            for (path, result) in results {
+                match result {
+                    Ok(()) => {}
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                    Err(e) => log::warn!("Failed to remove {}: {}", path.display(), e),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn handle(
+        &self,
+        myself: ActorRef<Self::Msg>,
+        message: Self::Msg,
+        state: &mut Self::State,
+    ) -> Result<(), ActorProcessingErr> {
+        log::info!("Handling message: '{:?}'", message);
+
+        match message {
+            IndexerActorMessage::Index => {
+                state.run_index().await;
+            }
+            IndexerActorMessage::AutoIndex(duration) if state.stale_tick == Some(duration) => {
+                log::debug!("Ignoring auto-index tick replaced by an IndexNow.");
+                state.stale_tick = None;
+            }
+            IndexerActorMessage::AutoIndex(duration)
+                if state.pending_tick.as_ref().is_some_and(|tick| {
+                    tick.interval == duration && !tick.sent.load(Ordering::Acquire)
+                }) =>
+            {
+                // e.g. sent while a run was in progress, the run already scheduled the next tick
+                log::info!("Ignoring auto-index tick, the next one is already scheduled.");
+            }
+            IndexerActorMessage::AutoIndex(duration) => {
+                if state
+                    .pending_tick
+                    .as_ref()
+                    .is_some_and(|tick| tick.interval == duration)
+                {
+                    state.pending_tick = None;
+                }
+
+                // check if the auto index originated from the current interval
+                if let Some(interval) = state.timer_interval
+                    && duration == interval
+                {
+                    state.run_index().await;
+
+                    // schedule next auto-index
+                    let delay = state.next_auto_index_delay(interval);
+                    if delay != interval {
+                        log::warn!("Backing off, next auto-index in {:?}.", delay);
+                    }
+                    state.schedule_tick(&myself, interval, delay);
+                } else {
+                    log::info!("Auto-indexing interval changed or stopped, not indexing.");
+                }
+            }
+            IndexerActorMessage::StartAutoIndex(duration)
+                if state.timer_interval == Some(duration) =>
+            {
+                // a second timer chain with the same interval would index twice as often
+                log::info!("Already auto-indexing every {:?}.", duration);
+            }
+            IndexerActorMessage::StartAutoIndex(duration) => {
+                log::info!("Starting auto-indexing every {:?}.", duration);
+                state.timer_interval = Some(duration);
+                state.paused_interval = None;
+                state.schedule_auto_index(&myself, duration);
+            }
+            IndexerActorMessage::StopAutoIndex => {
+                log::info!("Stopping auto-indexing.");
+                state.timer_interval = None;
+                state.paused_interval = None;
+            }
+            IndexerActorMessage::IndexNow => {
+                state.run_index().await;
+
+                if let Some(interval) = state.timer_interval {
+                    state.cancel_pending_tick().await;
+
+                    let delay = state.next_auto_index_delay(interval);
+                    log::info!("Next auto-index in {:?}.", delay);
+                    state.schedule_tick(&myself, interval, delay);
+                }
+            }
+            IndexerActorMessage::IndexRange { from, to } => {
+                if let Err(e) = state.index_range(&from, &to).await {
+                    log::error!("Failed to index range {}..{}: {:?}", from, to, e);
+                }
+            }
+            IndexerActorMessage::Pause => match state.timer_interval.take() {
+                Some(interval) => {
+                    log::info!("Pausing auto-indexing every {:?}.", interval);
+                    state.paused_interval = Some(interval);
+                }
+                None => log::warn!("Pause requested but auto-indexing isn't running."),
+            },
+            IndexerActorMessage::Resume => match state.paused_interval.take() {
+                Some(interval) => {
+                    log::info!("Resuming auto-indexing every {:?}.", interval);
+                    state.timer_interval = Some(interval);
+                    state.schedule_auto_index(&myself, interval);
+                }
+                None => log::warn!("Resume requested but auto-indexing isn't paused."),
+            },
+            IndexerActorMessage::Subscribe(sender) => {
+                state.subscribers.push(sender);
+            }
+            IndexerActorMessage::GetStatus(reply) => {
+                if reply.send(state.status()).is_err() {
+                    log::warn!("Status requested but the caller went away.");
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+
+    use super::*;
+    use crate::crates_index::CrateChange;
+    use crate::test_util::{
+        MockBackend, Remote, TempDir, backend_kinds, git, index_line, index_path, next_changes,
+        next_event,
+    };
+    use tokio::sync::mpsc::{self, Receiver, error::TryRecvError};
+
+    fn arguments(remote: &Remote, base_dir: &TempDir) -> IndexerActorArguments {
+        IndexerActorArguments::new(remote.url(), Some("clone".to_string()))
+            .with_base_dir(base_dir.path().to_path_buf())
+    }
+
+    /// Arguments of an actor polling `backend` instead of a real repository.
+    fn mock_arguments(backend: &Arc<MockBackend>, base_dir: &TempDir) -> IndexerActorArguments {
+        IndexerActorArguments::new(
+            "https://example.com/index.git".to_string(),
+            Some("index".to_string()),
+        )
+        .with_base_dir(base_dir.path().to_path_buf())
+        .with_backend(backend.clone())
+    }
+
+    fn add(name: &str) -> DiffAction {
+        DiffAction::Add(CrateChange::new(name, Some("1.0.0".to_string())))
+    }
+
+    async fn status(actor: &ActorRef<IndexerActorMessage>) -> IndexerStatus {
+        ractor::call!(actor, IndexerActorMessage::GetStatus).unwrap()
+    }
+
+    /// Fails if a [`IndexerEvent::Changes`] is waiting once the actor handled every message
+    /// sent so far.
+    async fn assert_no_changes(
+        actor: &ActorRef<IndexerActorMessage>,
+        events: &mut Receiver<IndexerEvent>,
+    ) {
+        status(actor).await;
+        loop {
+            match events.try_recv() {
+                Ok(IndexerEvent::Changes(batch)) => panic!("unexpected changes {:?}", batch),
+                Ok(_) => {}
+                Err(TryRecvError::Empty) => return,
+                Err(e) => panic!("{}", e),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_to_delete_a_directory_that_is_no_clone() {
+        let (remote, base_dir) = (Remote::new(), TempDir::new());
+        remote.commit(&[("file", "content")], "initial");
+        let notes = base_dir.path().join("clone/notes.txt");
+        std::fs::create_dir_all(notes.parent().unwrap()).unwrap();
+        std::fs::write(&notes, "important").unwrap();
+
+        for kind in backend_kinds() {
+            let arguments = arguments(&remote, &base_dir).with_backend_kind(kind);
+            let result = Actor::spawn(None, IndexerActor, arguments).await;
+
+            let err = result.err().unwrap().to_string();
+            assert!(err.contains("not deleting"), "{:?}: {}", kind, err);
+            assert_eq!(std::fs::read_to_string(&notes).unwrap(), "important");
+        }
+    }
+
+    #[tokio::test]
+    async fn recovers_from_an_interrupted_clone() {
+        let remote = Remote::new();
+        let head = remote.commit(&[("file", "content")], "initial");
+
+        // an empty directory and a git dir without refs, which git doesn't accept
+        let interrupted: [fn(&Path); 2] = [
+            |_| {},
+            |path| {
+                std::fs::write(path.join("HEAD"), "ref: refs/heads/master\n").unwrap();
+                std::fs::create_dir(path.join("objects")).unwrap();
+            },
+        ];
+        for (kind, prepare) in backend_kinds()
+            .into_iter()
+            .flat_map(|kind| interrupted.map(|prepare| (kind, prepare)))
+        {
+            let base_dir = TempDir::new();
+            let path = base_dir.path().join("clone");
+            std::fs::create_dir(&path).unwrap();
+            prepare(&path);
+
+            let arguments = arguments(&remote, &base_dir).with_backend_kind(kind);
+            let (actor, handle) = Actor::spawn(None, IndexerActor, arguments).await.unwrap();
+
+            assert_eq!(status(&actor).await.last_commit_hash, Some(head.clone()));
+            assert!(path.join("objects").is_dir());
+
+            actor.stop(None);
+            handle.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn clones_into_a_missing_absolute_base_dir() {
+        let remote = Remote::new();
+        let head = remote.commit(&[("file", "content")], "initial");
+
+        for kind in backend_kinds() {
+            let dir = TempDir::new();
+            let base_dir = dir.path().join("var/lib/poll-git");
+            let arguments = IndexerActorArguments::new(remote.url(), Some("clone".to_string()))
+                .with_base_dir(base_dir.clone())
+                .with_backend_kind(kind);
+            assert_eq!(arguments.repository_path(), base_dir.join("clone"));
+
+            let (actor, handle) = Actor::spawn(None, IndexerActor, arguments).await.unwrap();
+
+            assert_eq!(status(&actor).await.last_commit_hash, Some(head.clone()));
+            assert!(base_dir.join("clone/objects").is_dir(), "{:?}", kind);
+
+            actor.stop(None);
+            handle.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn announces_the_head_of_a_fresh_clone() {
+        let remote = Remote::new();
+        remote.commit(&[("file", "content")], "initial");
+        let head = remote.commit(&[("file", "changed")], "second");
+
+        for kind in backend_kinds() {
+            let base_dir = TempDir::new();
+            let (sender, mut events) = mpsc::channel(16);
+            let arguments = arguments(&remote, &base_dir)
+                .with_backend_kind(kind)
+                .with_subscriber(sender);
+            let (actor, handle) = Actor::spawn(None, IndexerActor, arguments.clone())
+                .await
+                .unwrap();
+
+            match next_event(&mut events).await {
+                IndexerEvent::Cloned { repo, head: cloned } => {
+                    assert_eq!(repo, "clone");
+                    assert_eq!(cloned.as_ref(), Some(&head), "{:?}", kind);
+                }
+                event => panic!("{:?}", event),
+            }
+            assert!(matches!(
+                next_event(&mut events).await,
+                IndexerEvent::Ready { head: ready, .. } if ready == head
+            ));
+            actor.stop(None);
+            handle.await.unwrap();
+
+            // reopening the clone is only ready
+            let (sender, mut events) = mpsc::channel(16);
+            let (actor, handle) =
+                Actor::spawn(None, IndexerActor, arguments.with_subscriber(sender))
+                    .await
+                    .unwrap();
+            assert!(matches!(
+                next_event(&mut events).await,
+                IndexerEvent::Ready { head: ready, .. } if ready == head
+            ));
+            actor.stop(None);
+            handle.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn removes_the_clone_on_stop_only_if_asked_to() {
+        let remote = Remote::new();
+        remote.commit(&[("file", "content")], "initial");
+
+        for cleanup_on_stop in [false, true] {
+            let base_dir = TempDir::new();
+            let arguments = arguments(&remote, &base_dir).with_cleanup_on_stop(cleanup_on_stop);
+            let path = arguments.repository_path();
+            let state_path = PollState::path_for(&path);
+            let (actor, handle) = Actor::spawn(None, IndexerActor, arguments).await.unwrap();
+
+            remote.commit(&[("file", "changed")], "update");
+            actor.cast(IndexerActorMessage::Index).unwrap();
+            status(&actor).await;
+            assert!(path.is_dir());
+            assert!(state_path.is_file());
+
+            actor.stop(None);
+            handle.await.unwrap();
+
+            assert_eq!(path.exists(), !cleanup_on_stop);
+            assert_eq!(state_path.exists(), !cleanup_on_stop);
+            assert!(base_dir.path().is_dir());
+        }
+    }
+
+    #[tokio::test]
+    async fn waits_for_the_first_commit_of_an_empty_remote() {
+        for kind in backend_kinds() {
+            let (remote, base_dir) = (Remote::new(), TempDir::new());
+            let (sender, mut events) = mpsc::channel(16);
+            let arguments = arguments(&remote, &base_dir)
+                .with_backend_kind(kind)
+                .with_subscriber(sender);
+            let (actor, handle) = Actor::spawn(None, IndexerActor, arguments).await.unwrap();
+            assert!(matches!(
+                next_event(&mut events).await,
+                IndexerEvent::Cloned { head: None, .. }
+            ));
+
+            actor.cast(IndexerActorMessage::Index).unwrap();
+            let empty = status(&actor).await;
+            assert_eq!(empty.last_commit_hash, None);
+            assert_eq!(empty.consecutive_failures, 0, "{:?}", kind);
+            assert!(matches!(events.try_recv(), Err(TryRecvError::Empty)));
+
+            // the first commit is the baseline
+            let head = remote.commit(
+                &[(&index_path("serde"), &index_line("serde", "1.0.0", false))],
+                "serde 1.0.0",
+            );
+            actor.cast(IndexerActorMessage::Index).unwrap();
+            assert!(matches!(
+                next_event(&mut events).await,
+                IndexerEvent::Ready { head: ready, .. } if ready == head
+            ));
+            assert_no_changes(&actor, &mut events).await;
+            assert_eq!(status(&actor).await.consecutive_failures, 0);
+
+            actor.stop(None);
+            handle.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn detects_a_force_push() {
+        for (kind, rebaseline) in backend_kinds()
+            .into_iter()
+            .flat_map(|kind| [(kind, false), (kind, true)])
+        {
+            let (remote, base_dir) = (Remote::new(), TempDir::new());
+            let first = remote.commit(&[("config.json", "{}")], "initial");
+            let dropped = remote.commit(
+                &[(&index_path("serde"), &index_line("serde", "1.0.0", false))],
+                "serde 1.0.0",
+            );
+            let (sender, mut events) = mpsc::channel(16);
+            let arguments = arguments(&remote, &base_dir)
+                .with_backend_kind(kind)
+                .with_subscriber(sender)
+                .with_rebaseline_on_rewrite(rebaseline);
+            let (actor, handle) = Actor::spawn(None, IndexerActor, arguments).await.unwrap();
+
+            git(remote.path(), &["reset", "-q", "--hard", &first]);
+            let rewritten = remote.commit(
+                &[(&index_path("tokio"), &index_line("tokio", "1.48.0", false))],
+                "tokio 1.48.0",
+            );
+            actor.cast(IndexerActorMessage::Index).unwrap();
+
+            loop {
+                if let IndexerEvent::HistoryRewrite {
+                    old_commit,
+                    new_commit,
+                    ..
+                } = next_event(&mut events).await
+                {
+                    assert_eq!(
+                        (old_commit, new_commit),
+                        (dropped.clone(), rewritten.clone())
+                    );
+                    break;
+                }
+            }
+            if rebaseline {
+                assert_no_changes(&actor, &mut events).await;
+            } else {
+                let batch = next_changes(&mut events).await;
+                assert_eq!(batch.old_commit.as_ref(), Some(&dropped));
+                assert_eq!(
+                    HashSet::<DiffAction>::from_iter(batch.actions),
+                    HashSet::from([
+                        DiffAction::Remove(CrateChange::new("serde", Some("1.0.0".to_string()))),
+                        DiffAction::Add(CrateChange::new("tokio", Some("1.48.0".to_string()))),
+                    ]),
+                    "{:?}",
+                    kind
+                );
+            }
+            assert_eq!(status(&actor).await.last_commit_hash, Some(rewritten));
+
+            actor.stop(None);
+            handle.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn starts_an_unfetched_clone_at_its_head() {
+        let remote = Remote::new();
+        let head = remote.commit(
+            &[(&index_path("serde"), &index_line("serde", "1.0.0", false))],
+            "serde 1.0.0",
+        );
+
+        for (kind, bare) in backend_kinds()
+            .into_iter()
+            .flat_map(|kind| [(kind, true), (kind, false)])
+        {
+            let base_dir = TempDir::new();
+            let options = CloneOptions {
+                bare,
+                ..CloneOptions::default()
+            };
+            // cloned earlier, but never fetched nor indexed
+            GitService::new(base_dir.path().join("clone"))
+                .clone_repository(&remote.url(), &options)
+                .await
+                .unwrap();
+            let (sender, mut events) = mpsc::channel(16);
+            let arguments = arguments(&remote, &base_dir)
+                .with_backend_kind(kind)
+                .with_clone_options(options)
+                .with_subscriber(sender);
+            let (actor, handle) = Actor::spawn(None, IndexerActor, arguments).await.unwrap();
+
+            assert_eq!(
+                status(&actor).await.last_commit_hash.as_ref(),
+                Some(&head),
+                "{:?}, bare: {}",
+                kind,
+                bare
+            );
+
+            remote.commit(
+                &[(&index_path("tokio"), &index_line("tokio", "1.48.0", false))],
+                "tokio 1.48.0",
+            );
+            actor.cast(IndexerActorMessage::Index).unwrap();
+            assert_eq!(
+                next_changes(&mut events).await.actions,
+                vec![DiffAction::Add(CrateChange::new(
+                    "tokio",
+                    Some("1.48.0".to_string())
+                ))]
+            );
+
+            actor.stop(None);
+            handle.await.unwrap();
+            git(remote.path(), &["reset", "-q", "--hard", &head]);
+        }
+    }
+
+    #[tokio::test]
+    async fn index_once_returns_the_actions_of_new_commits() {
+        for kind in backend_kinds() {
+            let (remote, base_dir) = (Remote::new(), TempDir::new());
+            remote.commit(
+                &[(&index_path("serde"), &index_line("serde", "1.0.0", false))],
+                "serde 1.0.0",
+            );
+            let arguments = arguments(&remote, &base_dir).with_backend_kind(kind);
+
+            // the fresh clone is the baseline
+            assert_eq!(index_once(&arguments, None).await.unwrap(), Vec::new());
+
+            let head = remote.commit(
+                &[(&index_path("tokio"), &index_line("tokio", "1.48.0", false))],
+                "tokio 1.48.0",
+            );
+
+            let actions = index_once(&arguments, None).await.unwrap();
+            assert_eq!(
+                actions,
+                vec![DiffAction::Add(CrateChange::new(
+                    "tokio",
+                    Some("1.48.0".to_string())
+                ))],
+                "{:?}",
+                kind
+            );
+            let state = PollState::load(&PollState::path_for(&arguments.repository_path())).await;
+            assert_eq!(state.unwrap().last_commit_hash, Some(head));
+
+            assert_eq!(index_once(&arguments, None).await.unwrap(), Vec::new());
+        }
+    }
+
+    #[tokio::test]
+    async fn emits_every_crate_version_once_per_cycle() {
+        let (remote, base_dir) = (Remote::new(), TempDir::new());
+        remote.commit(&[("config.json", "{}")], "initial");
+        let (sender, mut events) = mpsc::channel(16);
+        let arguments = arguments(&remote, &base_dir).with_subscriber(sender);
+        let (actor, handle) = Actor::spawn(None, IndexerActor, arguments).await.unwrap();
+
+        // the same version shows up twice in one file and once in another one
+        let line = index_line("serde", "1.0.0", false);
+        remote.commit(
+            &[
+                (&index_path("serde"), &format!("{}\n{}\n", line, line)),
+                ("mirror/serde", &line),
+            ],
+            "serde 1.0.0",
+        );
+        actor.cast(IndexerActorMessage::Index).unwrap();
+
+        let batch = next_changes(&mut events).await;
+        assert_eq!(
+            batch.actions,
+            vec![DiffAction::Add(CrateChange::new(
+                "serde",
+                Some("1.0.0".to_string())
+            ))]
+        );
+
+        actor.stop(None);
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn emits_a_range_only_once() {
+        let (remote, base_dir) = (Remote::new(), TempDir::new());
+        let from = remote.commit(&[("config.json", "{}")], "initial");
+        let to = remote.commit(
+            &[(&index_path("serde"), &index_line("serde", "1.0.0", false))],
+            "serde 1.0.0",
+        );
+        let index_range = || IndexerActorMessage::IndexRange {
+            from: from.clone(),
+            to: to.clone(),
+        };
+
+        let (sender, mut events) = mpsc::channel(16);
+        let arguments = arguments(&remote, &base_dir).with_subscriber(sender);
+        let (actor, handle) = Actor::spawn(None, IndexerActor, arguments.clone())
+            .await
+            .unwrap();
+        actor.cast(index_range()).unwrap();
+        assert_eq!(next_changes(&mut events).await.new_commit, to);
+        actor.cast(index_range()).unwrap();
+        assert_no_changes(&actor, &mut events).await;
+        actor.stop(None);
+        handle.await.unwrap();
+
+        // a restarted actor remembers the range
+        let (sender, mut events) = mpsc::channel(16);
+        let (actor, handle) = Actor::spawn(
+            None,
+            IndexerActor,
+            arguments.clone().with_subscriber(sender),
+        )
+        .await
+        .unwrap();
+        actor.cast(index_range()).unwrap();
+        assert_no_changes(&actor, &mut events).await;
+        actor.stop(None);
+        handle.await.unwrap();
+
+        // so does index_once
+        let base_dir = TempDir::new();
+        let arguments = arguments.with_base_dir(base_dir.path().to_path_buf());
+        assert_eq!(index_once(&arguments, Some(&from)).await.unwrap().len(), 1);
+        assert_eq!(
+            index_once(&arguments, Some(&from)).await.unwrap(),
+            Vec::new()
+        );
+    }
+
+    #[test]
+    fn derives_distinct_dir_names_for_the_same_last_segment() {
+        let github = get_dir_name_from_url("https://github.com/a/index.git");
+        let gitlab = get_dir_name_from_url("https://gitlab.com/b/index.git");
+
+        assert!(github.starts_with("index-"), "{}", github);
+        assert!(gitlab.starts_with("index-"), "{}", gitlab);
+        assert_ne!(github, gitlab);
+        // stays the same for the same url
+        assert_eq!(
+            get_dir_name_from_url("https://github.com/a/index.git"),
+            github
+        );
+    }
+
+    #[test]
+    fn sanitizes_derived_dir_names() {
+        assert!(get_dir_name_from_url("git@host:a/my repo.git").starts_with("my_repo-"));
+        assert!(get_dir_name_from_url("https://host/..").starts_with("repository-"));
+        assert_eq!(
+            IndexerActorArguments::new(
+                "https://github.com/a/index.git".to_string(),
+                Some("index".to_string())
+            )
+            .dir_name(),
+            "index"
+        );
+    }
+
+    #[tokio::test]
+    async fn resumes_from_the_saved_commit() {
+        let base_dir = TempDir::new();
+        let backend = Arc::new(MockBackend::new("c1"));
+        let (sender, mut events) = mpsc::channel(16);
+        let arguments = mock_arguments(&backend, &base_dir).with_subscriber(sender);
+        let (actor, handle) = Actor::spawn(None, IndexerActor, arguments).await.unwrap();
+        assert_eq!(status(&actor).await.last_commit_hash.as_deref(), Some("c1"));
+
+        backend.push("c1", "c2", [add("serde")]);
+        actor.cast(IndexerActorMessage::Index).unwrap();
+        next_changes(&mut events).await;
+        actor.stop(None);
+        handle.await.unwrap();
+
+        // a new clone at c1 continues from the saved c2
+        let restarted = |backend: &Arc<MockBackend>| {
+            Actor::spawn(None, IndexerActor, mock_arguments(backend, &base_dir))
+        };
+        let backend = Arc::new(MockBackend::new("c1"));
+        let (actor, handle) = restarted(&backend).await.unwrap();
+        assert_eq!(status(&actor).await.last_commit_hash.as_deref(), Some("c2"));
+        actor.stop(None);
+        handle.await.unwrap();
+
+        // without a usable state file the clone is the baseline
+        let state_path = PollState::path_for(&base_dir.path().join("index"));
+        std::fs::write(&state_path, "{ not json").unwrap();
+        let (actor, handle) = restarted(&backend).await.unwrap();
+        assert_eq!(status(&actor).await.last_commit_hash.as_deref(), Some("c1"));
+        actor.stop(None);
+        handle.await.unwrap();
+
+        std::fs::remove_file(&state_path).unwrap();
+        let (actor, handle) = restarted(&backend).await.unwrap();
+        assert_eq!(status(&actor).await.last_commit_hash.as_deref(), Some("c1"));
+        actor.stop(None);
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn sends_changes_to_subscribers() {
+        let base_dir = TempDir::new();
+        let backend = Arc::new(MockBackend::new("c1"));
+        let (actor, handle) = Actor::spawn(None, IndexerActor, mock_arguments(&backend, &base_dir))
+            .await
+            .unwrap();
+        let (sender, mut events) = mpsc::channel(16);
+        actor.cast(IndexerActorMessage::Subscribe(sender)).unwrap();
+        let (sender, dropped) = mpsc::channel(16);
+        actor.cast(IndexerActorMessage::Subscribe(sender)).unwrap();
+        drop(dropped);
+
+        backend.push("c1", "c2", [add("serde")]);
+        actor.cast(IndexerActorMessage::Index).unwrap();
+        let batch = next_changes(&mut events).await;
+        assert_eq!(
+            (batch.old_commit.as_deref(), batch.new_commit.as_str()),
+            (Some("c1"), "c2")
+        );
+        assert_eq!(batch.actions, vec![add("serde")]);
+
+        // the closed channel didn't stop the actor
+        backend.push("c2", "c3", [add("tokio")]);
+        actor.cast(IndexerActorMessage::Index).unwrap();
+        assert_eq!(next_changes(&mut events).await.actions, vec![add("tokio")]);
+
+        actor.stop(None);
+        handle.await.unwrap();
+    }
+
+    #[test]
+    fn removes_subscribers_whose_receiver_is_gone() {
+        let (open, _events) = mpsc::channel(16);
+        let (closed, dropped) = mpsc::channel(16);
+        drop(dropped);
+        let mut subscribers = vec![open, closed];
+
+        notify_subscribers(
+            &mut subscribers,
+            IndexerEvent::Ready {
+                repo: "index".to_string(),
+                head: "c1".to_string(),
+            },
+        );
+
+        assert_eq!(subscribers.len(), 1);
+        assert!(!subscribers[0].is_closed());
+    }
+
+    #[tokio::test]
+    async fn survives_a_failing_backend() {
+        let base_dir = TempDir::new();
+        let backend = Arc::new(MockBackend::new("c1"));
+        let (sender, mut events) = mpsc::channel(16);
+        let arguments = mock_arguments(&backend, &base_dir).with_subscriber(sender);
+        let (actor, handle) = Actor::spawn(None, IndexerActor, arguments).await.unwrap();
+
+        backend.push("c1", "c2", [add("serde")]);
+        backend.state().fail_fetch = true;
+        actor.cast(IndexerActorMessage::Index).unwrap();
+        actor.cast(IndexerActorMessage::Index).unwrap();
+        let failed = status(&actor).await;
+        assert_eq!(failed.last_commit_hash.as_deref(), Some("c1"));
+        assert_eq!(failed.consecutive_failures, 2);
+
+        backend.state().fail_fetch = false;
+        actor.cast(IndexerActorMessage::Index).unwrap();
+        let batch = next_changes(&mut events).await;
+        assert_eq!(batch.old_commit.as_deref(), Some("c1"));
+        assert_eq!(status(&actor).await.consecutive_failures, 0);
+
+        actor.stop(None);
+        handle.await.unwrap();
+    }
+
+    #[test]
+    fn doubles_the_backoff_up_to_the_cap() {
+        let (base, max) = (Duration::from_secs(5), Duration::from_secs(60));
+
+        let delays = (1..=7)
+            .map(|failures| backoff_delay(failures, base, max).as_secs())
+            .collect::<Vec<_>>();
+
+        assert_eq!(delays, [5, 10, 20, 40, 60, 60, 60]);
+        // doesn't overflow after many failures
+        assert_eq!(backoff_delay(u32::MAX, base, max), max);
+    }
+
+    #[test]
+    fn never_retries_sooner_than_the_interval() {
+        let (base, max) = (Duration::from_secs(30), Duration::from_secs(30 * 60));
+
+        let interval = Duration::from_secs(300);
+        let delays = (0..=8)
+            .map(|failures| auto_index_delay(interval, failures, base, max).as_secs())
+            .collect::<Vec<_>>();
+        assert_eq!(delays, [300, 300, 300, 300, 300, 480, 960, 1800, 1800]);
+
+        // an interval above the cap isn't shortened by it
+        let interval = Duration::from_secs(3600);
+        for failures in 0..=8 {
+            assert_eq!(auto_index_delay(interval, failures, base, max), interval);
+        }
+    }
+
+    #[test]
+    fn jitters_within_the_fraction() {
+        let delay = Duration::from_secs(60);
+        assert_eq!(jittered(delay, 0.0), delay);
+
+        let delays = (0..1000)
+            .map(|_| jittered(delay, 0.2))
+            .collect::<HashSet<_>>();
+        for jittered in &delays {
+            assert!(
+                (Duration::from_secs(48)..=Duration::from_secs(72)).contains(jittered),
+                "{:?}",
+                jittered
+            );
+        }
+        // spread out instead of one shifted delay
+        assert!(delays.iter().any(|jittered| *jittered < delay));
+        assert!(delays.iter().any(|jittered| *jittered > delay));
+    }
+
+    #[test]
+    fn clamps_the_jitter_fraction() {
+        let arguments = |fraction| {
+            IndexerActorArguments::new("https://example.com/index.git".to_string(), None)
+                .with_interval_jitter(fraction)
+                .interval_jitter
+        };
+
+        assert_eq!(arguments(-0.5), 0.0);
+        assert_eq!(arguments(0.1), 0.1);
+        assert_eq!(arguments(3.0), 1.0);
+    }
+
+    #[tokio::test]
+    async fn drops_ticks_arriving_during_a_run() {
+        let base_dir = TempDir::new();
+        let backend = Arc::new(MockBackend::new("c1"));
+        backend.state().diff_delay = Duration::from_millis(500);
+        backend.push("c1", "c2", [add("serde")]);
+        let (actor, handle) = Actor::spawn(None, IndexerActor, mock_arguments(&backend, &base_dir))
+            .await
+            .unwrap();
+
+        let interval = Duration::from_millis(300);
+        actor
+            .cast(IndexerActorMessage::StartAutoIndex(interval))
+            .unwrap();
+        while backend.state().diffed.is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        // the first tick is still diffing
+        actor
+            .cast(IndexerActorMessage::AutoIndex(interval))
+            .unwrap();
+        actor
+            .cast(IndexerActorMessage::AutoIndex(interval))
+            .unwrap();
+
+        let status = status(&actor).await;
+        assert_eq!(status.last_commit_hash.as_deref(), Some("c2"));
+        assert!(status.last_index_duration.unwrap() >= Duration::from_millis(500));
+        assert_eq!(backend.state().fetches.len(), 1);
+
+        actor.stop(None);
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn reports_its_status() {
+        let base_dir = TempDir::new();
+        let backend = Arc::new(MockBackend::new("c1"));
+        let (actor, handle) = Actor::spawn(None, IndexerActor, mock_arguments(&backend, &base_dir))
+            .await
+            .unwrap();
+
+        let before = status(&actor).await;
+        assert_eq!(before.last_indexed, None);
+        assert_eq!(before.last_commit_hash.as_deref(), Some("c1"));
+        assert!(!before.auto_indexing);
+        assert_eq!(before.timer_interval, None);
+        assert_eq!(before.consecutive_failures, 0);
+        assert_eq!(before.last_index_duration, None);
+
+        backend.push("c1", "c2", [add("serde")]);
+        actor.cast(IndexerActorMessage::Index).unwrap();
+        let interval = Duration::from_secs(3600);
+        actor
+            .cast(IndexerActorMessage::StartAutoIndex(interval))
+            .unwrap();
+
+        let after = status(&actor).await;
+        assert!(after.last_indexed.is_some());
+        assert_eq!(after.last_commit_hash.as_deref(), Some("c2"));
+        assert!(after.auto_indexing);
+        assert_eq!(after.timer_interval, Some(interval));
+        assert_eq!(after.consecutive_failures, 0);
+        assert!(after.last_index_duration.is_some());
+
+        actor.stop(None);
+        handle.await.unwrap();
+    }
+
+    /// Value of `name` for the repository `repository` in a Prometheus scrape, 0 if missing.
+    fn metric(scrape: &str, name: &str, repository: &str) -> f64 {
+        let prefix = format!("{}{{repository=\"{}\"}} ", name, repository);
+        scrape
+            .lines()
+            .find_map(|line| line.strip_prefix(&prefix))
+            .map(|value| value.parse().unwrap())
+            .unwrap_or_default()
+    }
+
+    #[tokio::test]
+    async fn records_metrics_of_every_cycle() {
+        // the recorder is global, the other tests use other repository names
+        let address = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        metrics_exporter_prometheus::PrometheusBuilder::new()
+            .with_http_listener(address)
+            .install()
+            .unwrap();
+        let scrape = || async move {
+            for _ in 0..50 {
+                match reqwest::get(format!("http://{}/metrics", address)).await {
+                    Ok(response) => return response.text().await.unwrap(),
+                    Err(_) => tokio::time::sleep(Duration::from_millis(20)).await,
+                }
+            }
+            panic!("metrics endpoint on {} didn't answer", address);
+        };
+
+        let base_dir = TempDir::new();
+        let backend = Arc::new(MockBackend::new("c1"));
+        let arguments = IndexerActorArguments::new(
+            "https://example.com/metrics.git".to_string(),
+            Some("metrics".to_string()),
+        )
+        .with_base_dir(base_dir.path().to_path_buf())
+        .with_backend(backend.clone());
+        let (actor, handle) = Actor::spawn(None, IndexerActor, arguments).await.unwrap();
+
+        backend.state().fail_fetch = true;
+        actor.cast(IndexerActorMessage::Index).unwrap();
+        status(&actor).await;
+        let failed = scrape().await;
+        assert_eq!(metric(&failed, "indexer_cycles_total", "metrics"), 1.0);
+        assert_eq!(
+            metric(&failed, "indexer_fetch_failures_total", "metrics"),
+            1.0
+        );
+
+        backend.state().fail_fetch = false;
+        backend.push("c1", "c2", [add("serde"), add("tokio")]);
+        actor.cast(IndexerActorMessage::Index).unwrap();
+        status(&actor).await;
+        let indexed = scrape().await;
+        assert_eq!(metric(&indexed, "indexer_cycles_total", "metrics"), 2.0);
+        assert_eq!(
+            metric(&indexed, "indexer_cycles_with_changes_total", "metrics"),
+            1.0
+        );
+        assert_eq!(metric(&indexed, "indexer_diff_actions_sum", "metrics"), 2.0);
+        assert!(
+            indexed.contains("indexer_last_index_duration_seconds{repository=\"metrics\"}"),
+            "{}",
+            indexed
+        );
+
+        actor.stop(None);
+        handle.await.unwrap();
+    }
+
+    /// Fields of every closed `index` span.
+    #[derive(Clone, Default)]
+    struct IndexSpans(Arc<std::sync::Mutex<Vec<HashMap<String, String>>>>);
+
+    struct SpanFields(HashMap<String, String>);
+
+    impl tracing::field::Visit for SpanFields {
+        fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+            self.0.insert(field.name().to_string(), value.to_string());
+        }
+
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+            self.0
+                .insert(field.name().to_string(), format!("{:?}", value));
+        }
+    }
+
+    impl<S> tracing_subscriber::Layer<S> for IndexSpans
+    where
+        S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+    {
+        fn on_new_span(
+            &self,
+            attrs: &tracing::span::Attributes<'_>,
+            id: &tracing::span::Id,
+            ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            let mut fields = SpanFields(HashMap::new());
+            attrs.record(&mut fields);
+            ctx.span(id).unwrap().extensions_mut().insert(fields);
+        }
+
+        fn on_record(
+            &self,
+            id: &tracing::span::Id,
+            values: &tracing::span::Record<'_>,
+            ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            if let Some(fields) = ctx
+                .span(id)
+                .unwrap()
+                .extensions_mut()
+                .get_mut::<SpanFields>()
+            {
+                values.record(fields);
+            }
+        }
+
+        fn on_close(&self, id: tracing::span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
+            let span = ctx.span(&id).unwrap();
+            if span.name() == "index"
+                && let Some(fields) = span.extensions_mut().remove::<SpanFields>()
+            {
+                self.0.lock().unwrap().push(fields.0);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn records_the_commits_of_a_cycle_in_its_span() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        // the current thread runtime runs the actor on this thread, where the subscriber is set
+        let spans = IndexSpans::default();
+        let _guard =
+            tracing::subscriber::set_default(tracing_subscriber::registry().with(spans.clone()));
+
+        let base_dir = TempDir::new();
+        let backend = Arc::new(MockBackend::new("c1"));
+        let (actor, handle) = Actor::spawn(None, IndexerActor, mock_arguments(&backend, &base_dir))
+            .await
+            .unwrap();
+        backend.push("c1", "c2", [add("serde")]);
+        actor.cast(IndexerActorMessage::Index).unwrap();
+        status(&actor).await;
+
+        let spans = spans.0.lock().unwrap().clone();
+        let fields = |key: &str| spans.last().unwrap().get(key).cloned();
+        assert_eq!(spans.len(), 1);
+        assert_eq!(fields("repository").as_deref(), Some("index"));
+        assert_eq!(fields("old_commit").as_deref(), Some("c1"));
+        assert_eq!(fields("new_commit").as_deref(), Some("c2"));
+
+        actor.stop(None);
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn drives_every_commit_transition() {
+        let base_dir = TempDir::new();
+        // an empty remote, cloned without commits
+        let backend = Arc::new(MockBackend::default());
+        let (sender, mut events) = mpsc::channel(16);
+        let arguments = mock_arguments(&backend, &base_dir).with_subscriber(sender);
+        let (actor, handle) = Actor::spawn(None, IndexerActor, arguments).await.unwrap();
+        let index = || actor.cast(IndexerActorMessage::Index).unwrap();
+
+        // no commit before and after
+        index();
+        assert_eq!(status(&actor).await.last_commit_hash, None);
+        assert_no_changes(&actor, &mut events).await;
+
+        // the first commit is the baseline
+        backend.state().head = Some("c1".to_string());
+        index();
+        assert!(matches!(
+            next_event(&mut events).await,
+            IndexerEvent::Ready { head, .. } if head == "c1"
+        ));
+
+        // a new commit is diffed
+        backend.push("c1", "c2", [add("serde")]);
+        index();
+        assert_eq!(next_changes(&mut events).await.actions, vec![add("serde")]);
+
+        // no new commit
+        index();
+        assert_no_changes(&actor, &mut events).await;
+        assert_eq!(status(&actor).await.last_commit_hash.as_deref(), Some("c2"));
+
+        // a force push is reported and still diffed
+        backend.state().rewritten.insert("c2".to_string());
+        backend.push("c2", "d3", [add("tokio")]);
+        index();
+        assert!(matches!(
+            next_event(&mut events).await,
+            IndexerEvent::HistoryRewrite { old_commit, new_commit, .. }
+                if old_commit == "c2" && new_commit == "d3"
+        ));
+        assert_eq!(next_changes(&mut events).await.actions, vec![add("tokio")]);
+
+        // the commits are gone
+        backend.state().head = None;
+        index();
+        assert_no_changes(&actor, &mut events).await;
+        assert_eq!(status(&actor).await.last_commit_hash, None);
+        assert_eq!(backend.state().diffed.len(), 2);
+
+        actor.stop(None);
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn neither_diffs_rebaselined_rewrites_nor_emitted_ranges() {
+        let base_dir = TempDir::new();
+        let backend = Arc::new(MockBackend::new("c1"));
+        // the range was emitted, but the actor stopped before saving its new commit
+        PollState {
+            last_commit_hash: Some("c1".to_string()),
+            last_emitted: Some(EmittedRange::new(Some("c1"), "c2")),
+        }
+        .save(&PollState::path_for(&base_dir.path().join("index")))
+        .await
+        .unwrap();
+        let (sender, mut events) = mpsc::channel(16);
+        let arguments = mock_arguments(&backend, &base_dir)
+            .with_subscriber(sender)
+            .with_rebaseline_on_rewrite(true);
+        let (actor, handle) = Actor::spawn(None, IndexerActor, arguments).await.unwrap();
+
+        backend.push("c1", "c2", [add("serde")]);
+        actor.cast(IndexerActorMessage::Index).unwrap();
+        assert_no_changes(&actor, &mut events).await;
+        assert_eq!(status(&actor).await.last_commit_hash.as_deref(), Some("c2"));
+
+        backend.state().rewritten.insert("c2".to_string());
+        backend.push("c2", "d3", [add("tokio")]);
+        actor.cast(IndexerActorMessage::Index).unwrap();
+        assert!(matches!(
+            next_event(&mut events).await,
+            IndexerEvent::HistoryRewrite { .. }
+        ));
+        assert_no_changes(&actor, &mut events).await;
+        assert_eq!(status(&actor).await.last_commit_hash.as_deref(), Some("d3"));
+        assert!(backend.state().diffed.is_empty());
+
+        actor.stop(None);
+        handle.await.unwrap();
+    }
+
+    /// A remote whose `HEAD` moves from h1 to h2 and whose `stable` branch from s1 to s2.
+    fn two_branches() -> Arc<MockBackend> {
+        let backend = Arc::new(MockBackend::new("h1"));
+        backend.push("h1", "h2", [add("tokio")]);
+        let mut state = backend.state();
+        state.revs.insert("stable".to_string(), "s1".to_string());
+        state
+            .branches
+            .insert("stable".to_string(), "s2".to_string());
+        state.diffs.insert(
+            ("s1".to_string(), "s2".to_string()),
+            HashSet::from([add("serde")]),
+        );
+        drop(state);
+
+        backend
+    }
+
+    #[tokio::test]
+    async fn polls_the_remote_head_by_default() {
+        let (base_dir, backend) = (TempDir::new(), two_branches());
+        let (sender, mut events) = mpsc::channel(16);
+        let arguments = mock_arguments(&backend, &base_dir).with_subscriber(sender);
+        let (actor, handle) = Actor::spawn(None, IndexerActor, arguments).await.unwrap();
+        assert_eq!(status(&actor).await.last_commit_hash.as_deref(), Some("h1"));
+
+        actor.cast(IndexerActorMessage::Index).unwrap();
+
+        let batch = next_changes(&mut events).await;
+        assert_eq!(
+            (batch.old_commit.as_deref(), batch.new_commit.as_str()),
+            (Some("h1"), "h2")
+        );
+        assert_eq!(batch.actions, vec![add("tokio")]);
+        assert_eq!(backend.state().fetches, [None]);
+
+        actor.stop(None);
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn polls_only_the_pinned_branch() {
+        let (base_dir, backend) = (TempDir::new(), two_branches());
+        let (sender, mut events) = mpsc::channel(16);
+        let arguments = mock_arguments(&backend, &base_dir)
+            .with_subscriber(sender)
+            .with_branch("stable".to_string());
+        let (actor, handle) = Actor::spawn(None, IndexerActor, arguments).await.unwrap();
+        assert_eq!(status(&actor).await.last_commit_hash.as_deref(), Some("s1"));
+
+        actor.cast(IndexerActorMessage::Index).unwrap();
+
+        let batch = next_changes(&mut events).await;
+        assert_eq!(
+            (batch.old_commit.as_deref(), batch.new_commit.as_str()),
+            (Some("s1"), "s2")
+        );
+        assert_eq!(batch.actions, vec![add("serde")]);
+        assert_eq!(backend.state().fetches, [Some("stable".to_string())]);
+
+        actor.stop(None);
+        handle.await.unwrap();
+    }
+
+    /// Waits until `backend` was fetched `count` times.
+    async fn wait_for_fetches(backend: &MockBackend, count: usize) {
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while backend.state().fetches.len() < count {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the backend wasn't fetched often enough");
+    }
+
+    #[tokio::test]
+    async fn resumes_at_the_paused_interval() {
+        let base_dir = TempDir::new();
+        let backend = Arc::new(MockBackend::new("c1"));
+        let (actor, handle) = Actor::spawn(None, IndexerActor, mock_arguments(&backend, &base_dir))
+            .await
+            .unwrap();
+
+        let interval = Duration::from_millis(100);
+        actor
+            .cast(IndexerActorMessage::StartAutoIndex(interval))
+            .unwrap();
+        wait_for_fetches(&backend, 2).await;
+
+        actor.cast(IndexerActorMessage::Pause).unwrap();
+        let paused = status(&actor).await;
+        assert!(!paused.auto_indexing);
+        assert_eq!(paused.timer_interval, None);
+        assert_eq!(paused.paused_interval, Some(interval));
+        let fetched = backend.state().fetches.len();
+        tokio::time::sleep(interval * 3).await;
+        assert_eq!(backend.state().fetches.len(), fetched);
+
+        actor.cast(IndexerActorMessage::Resume).unwrap();
+        let resumed = status(&actor).await;
+        assert_eq!(resumed.timer_interval, Some(interval));
+        assert_eq!(resumed.paused_interval, None);
+        let started = Instant::now();
+        wait_for_fetches(&backend, fetched + 3).await;
+        // a single chain of ticks, the next tick is only scheduled once a run finished
+        assert!(started.elapsed() >= interval * 2, "{:?}", started.elapsed());
+
+        // pausing and resuming right away keeps the pending tick instead of adding a chain
+        actor.cast(IndexerActorMessage::Pause).unwrap();
+        actor.cast(IndexerActorMessage::Resume).unwrap();
+        let fetched = backend.state().fetches.len();
+        let started = Instant::now();
+        wait_for_fetches(&backend, fetched + 3).await;
+        assert!(started.elapsed() >= interval * 2, "{:?}", started.elapsed());
+
+        actor.stop(None);
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn ignores_resume_without_pause() {
+        let base_dir = TempDir::new();
+        let backend = Arc::new(MockBackend::new("c1"));
+        let (actor, handle) = Actor::spawn(None, IndexerActor, mock_arguments(&backend, &base_dir))
+            .await
+            .unwrap();
+
+        actor.cast(IndexerActorMessage::Resume).unwrap();
+        let status = status(&actor).await;
+        assert_eq!(status.timer_interval, None);
+        assert_eq!(status.paused_interval, None);
+
+        actor.stop(None);
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn keeps_auto_indexing_with_jitter() {
+        let base_dir = TempDir::new();
+        let backend = Arc::new(MockBackend::new("c1"));
+        let arguments = mock_arguments(&backend, &base_dir).with_interval_jitter(0.5);
+        let (actor, handle) = Actor::spawn(None, IndexerActor, arguments).await.unwrap();
+
+        // the ticks carry the base interval, a jittered one wouldn't match it
+        actor
+            .cast(IndexerActorMessage::StartAutoIndex(Duration::from_millis(
+                50,
+            )))
+            .unwrap();
+        wait_for_fetches(&backend, 3).await;
+
+        actor.stop(None);
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn indexes_a_range_on_demand() {
+        let base_dir = TempDir::new();
+        let backend = Arc::new(MockBackend::new("c3"));
+        backend.push("c1", "c2", [add("serde")]);
+        backend.push("c2", "c3", [add("tokio")]);
+        backend.state().revs.extend(
+            [("v1", "c1"), ("v2", "c2")].map(|(rev, commit)| (rev.to_string(), commit.to_string())),
+        );
+        let (sender, mut events) = mpsc::channel(16);
+        let arguments = mock_arguments(&backend, &base_dir).with_subscriber(sender);
+        let (actor, handle) = Actor::spawn(None, IndexerActor, arguments.clone())
+            .await
+            .unwrap();
+
+        actor
+            .cast(IndexerActorMessage::IndexRange {
+                from: "v1".to_string(),
+                to: "v2".to_string(),
+            })
+            .unwrap();
+
+        let batch = next_changes(&mut events).await;
+        assert_eq!(
+            (batch.old_commit.as_deref(), batch.new_commit.as_str()),
+            (Some("c1"), "c2")
+        );
+        assert_eq!(batch.actions, vec![add("serde")]);
+        let status = status(&actor).await;
+        assert_eq!(status.last_commit_hash.as_deref(), Some("c3"));
+        assert_eq!(status.timer_interval, None);
+        assert!(backend.state().fetches.is_empty());
+
+        // an unknown revision is reported before anything is diffed
+        let mut state = IndexerActor
+            .pre_start(actor.clone(), arguments)
+            .await
+            .unwrap();
+        let result = state.index_range("v1", "missing").await;
+        assert!(
+            matches!(&result, Err(IndexError::UnknownRevision(rev)) if rev == "missing"),
+            "{:?}",
+            result
+        );
+        assert_eq!(backend.state().diffed.len(), 1);
+
+        actor.stop(None);
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn index_now_restarts_the_schedule() {
+        let base_dir = TempDir::new();
+        let backend = Arc::new(MockBackend::new("c1"));
+        backend.state().diff_delay = Duration::from_millis(300);
+        backend.push("c1", "c2", [add("serde")]);
+        let (actor, handle) = Actor::spawn(None, IndexerActor, mock_arguments(&backend, &base_dir))
+            .await
+            .unwrap();
+
+        let interval = Duration::from_millis(200);
+        actor
+            .cast(IndexerActorMessage::StartAutoIndex(interval))
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        // the first tick is sent while this run is still diffing
+        actor.cast(IndexerActorMessage::IndexNow).unwrap();
+        assert_eq!(status(&actor).await.last_commit_hash.as_deref(), Some("c2"));
+        let finished = Instant::now();
+
+        tokio::time::sleep(interval / 2).await;
+        assert_eq!(backend.state().fetches.len(), 1);
+        wait_for_fetches(&backend, 2).await;
+        assert!(
+            finished.elapsed() >= interval * 3 / 4,
+            "{:?}",
+            finished.elapsed()
+        );
+
+        actor.stop(None);
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn delivers_a_large_diff_as_one_batch() {
+        let base_dir = TempDir::new();
+        let backend = Arc::new(MockBackend::new("c1"));
+        let names = (0..5000)
+            .map(|i| format!("crate-{}", i))
+            .collect::<Vec<_>>();
+        backend.push("c1", "c2", names.iter().map(|name| add(name)));
+        let (sender, mut events) = mpsc::channel(16);
+        let (broadcast, mut batches) = broadcast::channel(16);
+        let arguments = mock_arguments(&backend, &base_dir)
+            .with_subscriber(sender)
+            .with_broadcast(broadcast);
+        #[cfg(feature = "sqlite")]
+        let arguments = arguments.with_database(base_dir.path().join("changes.db"));
+        let (actor, handle) = Actor::spawn(None, IndexerActor, arguments).await.unwrap();
+
+        actor.cast(IndexerActorMessage::Index).unwrap();
+
+        let batch = next_changes(&mut events).await;
+        assert_eq!(
+            (batch.old_commit.as_deref(), batch.new_commit.as_str()),
+            (Some("c1"), "c2")
+        );
+        assert_eq!(batch.actions.len(), names.len());
+        assert_no_changes(&actor, &mut events).await;
+        assert_eq!(batches.try_recv().unwrap().actions.len(), names.len());
+        assert!(batches.try_recv().is_err());
+
+        #[cfg(feature = "sqlite")]
+        {
+            let stored = DiffStore::open(&base_dir.path().join("changes.db"))
+                .await
+                .unwrap()
+                .query(crate::store::ChangeQuery {
+                    limit: 10_000,
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+            assert_eq!(stored.len(), names.len());
+            assert!(stored.iter().all(|stored| stored.commit_hash == "c2"));
+        }
+
+        actor.stop(None);
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn leaves_the_persisted_state_alone_in_a_dry_run() {
+        let base_dir = TempDir::new();
+        let backend = Arc::new(MockBackend::new("c1"));
+        backend.push("c1", "c2", [add("serde")]);
+        let (sender, mut events) = mpsc::channel(16);
+        let arguments = mock_arguments(&backend, &base_dir)
+            .with_dry_run(true)
+            .with_subscriber(sender);
+        #[cfg(feature = "sqlite")]
+        let arguments = arguments.with_database(base_dir.path().join("changes.db"));
+        let state_path = PollState::path_for(&arguments.repository_path());
+        PollState {
+            last_commit_hash: Some("c1".to_string()),
+            last_emitted: None,
+        }
+        .save(&state_path)
+        .await
+        .unwrap();
+        let saved = std::fs::read(&state_path).unwrap();
+
+        let (actor, handle) = Actor::spawn(None, IndexerActor, arguments.clone())
+            .await
+            .unwrap();
+        actor.cast(IndexerActorMessage::Index).unwrap();
+
+        // the changes are still reported
+        assert_eq!(next_changes(&mut events).await.actions, vec![add("serde")]);
+        assert_eq!(status(&actor).await.last_commit_hash.as_deref(), Some("c2"));
+        actor.stop(None);
+        handle.await.unwrap();
+
+        assert_eq!(
+            index_once(&arguments, None).await.unwrap(),
+            vec![add("serde")]
+        );
+
+        assert_eq!(std::fs::read(&state_path).unwrap(), saved);
+        assert!(!base_dir.path().join("changes.db").exists());
     }
 }
@@ -0,0 +1,459 @@
+use std::collections::{HashMap, HashSet};
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+use tracing::log;
+
+use crate::git::{ChangeKind, GitError, LineChange};
+
+/// What [`diff_actions`] does with a changed line it can't parse.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DiffErrorMode {
+    /// Log and ignore the line.
+    #[default]
+    Skip,
+    /// Abort the diff with [`GitError::JsonError`] or [`GitError::DiffContentError`].
+    Fail,
+}
+
+/// Crate and version a line of the crates.io index describes.
+#[derive(Debug, Clone, Hash, PartialEq, Eq, Serialize)]
+pub struct CrateChange {
+    pub name: String,
+    /// `vers` of the line, `None` if it has none.
+    pub version: Option<String>,
+}
+
+impl CrateChange {
+    pub fn new(name: impl Into<String>, version: Option<String>) -> Self {
+        Self {
+            name: name.into(),
+            version,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum DiffAction {
+    Add(CrateChange),
+    /// A line of the crate was removed and another one added, carries the added version.
+    Update(CrateChange),
+    Remove(CrateChange),
+    /// The line of a version only changed its `yanked` flag.
+    #[serde(rename = "yank")]
+    YankChange {
+        #[serde(flatten)]
+        change: CrateChange,
+        /// Whether the version is yanked now.
+        yanked: bool,
+    },
+}
+
+impl DiffAction {
+    /// Name of the variant, as used by the store.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            DiffAction::Add(_) => "add",
+            DiffAction::Update(_) => "update",
+            DiffAction::Remove(_) => "remove",
+            DiffAction::YankChange { .. } => "yank",
+        }
+    }
+
+    /// Crate and version the action belongs to.
+    pub fn change(&self) -> &CrateChange {
+        match self {
+            DiffAction::Add(change)
+            | DiffAction::Update(change)
+            | DiffAction::Remove(change)
+            | DiffAction::YankChange { change, .. } => change,
+        }
+    }
+
+    /// Name of the crate the action belongs to.
+    pub fn name(&self) -> &str {
+        &self.change().name
+    }
+
+    /// Version of the crate the action belongs to, if the line had one.
+    pub fn version(&self) -> Option<&str> {
+        self.change().version.as_deref()
+    }
+
+    /// New `yanked` flag of a [`DiffAction::YankChange`].
+    pub fn yanked(&self) -> Option<bool> {
+        match self {
+            DiffAction::YankChange { yanked, .. } => Some(*yanked),
+            _ => None,
+        }
+    }
+
+    /// Inverse of [`DiffAction::kind`], a yank needs its `yanked` flag.
+    pub fn from_kind(kind: &str, change: CrateChange, yanked: Option<bool>) -> Option<Self> {
+        match kind {
+            "add" => Some(DiffAction::Add(change)),
+            "update" => Some(DiffAction::Update(change)),
+            "remove" => Some(DiffAction::Remove(change)),
+            "yank" => yanked.map(|yanked| DiffAction::YankChange { change, yanked }),
+            _ => None,
+        }
+    }
+}
+
+/// Turns the changed lines of the crates.io index into [`DiffAction`]s.
+///
+/// Every version of a crate gets at most one action for the whole diff, no matter in how
+/// many files or lines it shows up.
+pub fn diff_actions(
+    changes: &[LineChange],
+    mode: DiffErrorMode,
+) -> Result<HashSet<DiffAction>, GitError> {
+    let (mut added, mut removed) = (HashMap::new(), HashMap::new());
+
+    for change in changes {
+        match parse_line(&change.line) {
+            Ok((crate_change, value)) => {
+                let lines = match change.kind {
+                    ChangeKind::Added => &mut added,
+                    ChangeKind::Removed => &mut removed,
+                };
+                lines.insert(crate_change, value);
+            }
+            Err(err) => match mode {
+                DiffErrorMode::Skip => {
+                    log::warn!(
+                        "Skipping diff line '{}' in {}: {:?}",
+                        change.line,
+                        change.path,
+                        err
+                    )
+                }
+                DiffErrorMode::Fail => return Err(err),
+            },
+        }
+    }
+
+    Ok(classify(added, removed))
+}
+
+/// Parses a line of the crates.io index and extracts its crate name and version.
+fn parse_line(raw: &str) -> Result<(CrateChange, Value), GitError> {
+    let value: Value = serde_json::from_str(raw)?;
+
+    let name = value["name"]
+        .as_str()
+        .ok_or_else(|| GitError::DiffContentError(format!("Missing 'name' in '{}'", raw)))?;
+    let version = value["vers"].as_str().map(str::to_string);
+
+    Ok((CrateChange::new(name, version), value))
+}
+
+/// Pairs the added and removed lines of the same crate.
+///
+/// Lines of the same version pair up first, a pair is a [`DiffAction::YankChange`] if only
+/// the `yanked` flag differs and a [`DiffAction::Update`] otherwise. The remaining added and
+/// removed versions of a crate pair up into an [`DiffAction::Update`] to the added version,
+/// lines without a counterpart are added or removed versions.
+fn classify(
+    added: HashMap<CrateChange, Value>,
+    mut removed: HashMap<CrateChange, Value>,
+) -> HashSet<DiffAction> {
+    let mut actions = HashSet::new();
+    let mut unpaired = Vec::new();
+
+    for (change, new) in added {
+        match removed.remove(&change) {
+            Some(old) => {
+                actions.insert(match yank_change(&old, &new) {
+                    Some(yanked) => DiffAction::YankChange { change, yanked },
+                    None => DiffAction::Update(change),
+                });
+            }
+            None => unpaired.push(change),
+        }
+    }
+
+    // sorted, so several versions of a crate always pair up the same way
+    let by_version =
+        |a: &CrateChange, b: &CrateChange| (&a.name, &a.version).cmp(&(&b.name, &b.version));
+    let mut removed = removed.into_keys().collect::<Vec<_>>();
+    removed.sort_by(by_version);
+    unpaired.sort_by(by_version);
+
+    for change in unpaired {
+        match removed.iter().position(|old| old.name == change.name) {
+            Some(i) => {
+                removed.remove(i);
+                actions.insert(DiffAction::Update(change));
+            }
+            None => {
+                actions.insert(DiffAction::Add(change));
+            }
+        }
+    }
+    actions.extend(removed.into_iter().map(DiffAction::Remove));
+
+    actions
+}
+
+/// The new `yanked` flag if it's the only field that differs between the two lines.
+///
+/// A missing flag counts as not yanked.
+fn yank_change(old: &Value, new: &Value) -> Option<bool> {
+    const YANKED: &str = "yanked";
+
+    let (Value::Object(old), Value::Object(new)) = (old, new) else {
+        return None;
+    };
+    let is_yanked =
+        |line: &Map<String, Value>| line.get(YANKED).and_then(Value::as_bool).unwrap_or(false);
+    let other_fields = |line: &Map<String, Value>| line.keys().filter(|key| *key != YANKED).count();
+
+    let yanked = is_yanked(new);
+    let only_yank_differs = yanked != is_yanked(old)
+        && other_fields(old) == other_fields(new)
+        && old
+            .iter()
+            .all(|(key, value)| key == YANKED || new.get(key) == Some(value));
+
+    only_yank_differs.then_some(yanked)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{index_line, index_path};
+
+    fn added(line: String) -> LineChange {
+        LineChange {
+            path: "se/rd/serde".to_string(),
+            kind: ChangeKind::Added,
+            line,
+        }
+    }
+
+    fn removed(line: String) -> LineChange {
+        LineChange {
+            kind: ChangeKind::Removed,
+            ..added(line)
+        }
+    }
+
+    fn change(name: &str, version: &str) -> CrateChange {
+        CrateChange::new(name, Some(version.to_string()))
+    }
+
+    fn actions(changes: &[LineChange]) -> HashSet<DiffAction> {
+        diff_actions(changes, DiffErrorMode::Fail).unwrap()
+    }
+
+    #[test]
+    fn captures_name_and_version_of_index_lines() {
+        let changes = [
+            added(index_line("serde", "1.0.228", false)),
+            LineChange {
+                path: index_path("ab"),
+                ..removed(index_line("ab", "0.1.0-alpha.1", false))
+            },
+        ];
+
+        assert_eq!(
+            actions(&changes),
+            HashSet::from([
+                DiffAction::Add(change("serde", "1.0.228")),
+                DiffAction::Remove(change("ab", "0.1.0-alpha.1")),
+            ])
+        );
+    }
+
+    #[test]
+    fn falls_back_to_no_version() {
+        let changes = [added(r#"{"name":"serde","deps":[]}"#.to_string())];
+
+        assert_eq!(
+            actions(&changes),
+            HashSet::from([DiffAction::Add(CrateChange::new("serde", None))])
+        );
+    }
+
+    #[test]
+    fn pairs_versions_of_the_same_crate_into_an_update() {
+        let changes = [
+            removed(index_line("serde", "1.0.0", false)),
+            added(index_line("serde", "1.0.1", false)),
+        ];
+
+        assert_eq!(
+            actions(&changes),
+            HashSet::from([DiffAction::Update(change("serde", "1.0.1"))])
+        );
+    }
+
+    #[test]
+    fn pairs_the_same_version_before_other_versions() {
+        // yanks 1.0.0 and replaces 0.9.0 with 1.0.1 in the same diff
+        let changes = [
+            removed(index_line("serde", "0.9.0", false)),
+            removed(index_line("serde", "1.0.0", false)),
+            added(index_line("serde", "1.0.0", true)),
+            added(index_line("serde", "1.0.1", false)),
+        ];
+
+        assert_eq!(
+            actions(&changes),
+            HashSet::from([
+                DiffAction::YankChange {
+                    change: change("serde", "1.0.0"),
+                    yanked: true,
+                },
+                DiffAction::Update(change("serde", "1.0.1")),
+            ])
+        );
+    }
+
+    #[test]
+    fn classifies_yanks_unyanks_and_publishes() {
+        let yank = [
+            removed(index_line("serde", "1.0.0", false)),
+            added(index_line("serde", "1.0.0", true)),
+        ];
+        let unyank = [
+            removed(index_line("serde", "1.0.0", true)),
+            added(index_line("serde", "1.0.0", false)),
+        ];
+        let publish = [added(index_line("serde", "1.0.1", false))];
+
+        assert_eq!(
+            actions(&yank),
+            HashSet::from([DiffAction::YankChange {
+                change: change("serde", "1.0.0"),
+                yanked: true,
+            }])
+        );
+        assert_eq!(
+            actions(&unyank),
+            HashSet::from([DiffAction::YankChange {
+                change: change("serde", "1.0.0"),
+                yanked: false,
+            }])
+        );
+        assert_eq!(
+            actions(&publish),
+            HashSet::from([DiffAction::Add(change("serde", "1.0.1"))])
+        );
+    }
+
+    #[test]
+    fn only_counts_a_lone_yanked_flag_as_a_yank_change() {
+        let line = |json: &str| serde_json::from_str::<Value>(json).unwrap();
+        let unyanked = line(r#"{"name":"serde","vers":"1.0.0","yanked":false}"#);
+
+        assert_eq!(
+            yank_change(
+                &unyanked,
+                &line(r#"{"name":"serde","vers":"1.0.0","yanked":true}"#)
+            ),
+            Some(true)
+        );
+        // a missing flag is the same as not yanked
+        assert_eq!(
+            yank_change(
+                &line(r#"{"name":"serde","vers":"1.0.0"}"#),
+                &line(r#"{"name":"serde","vers":"1.0.0","yanked":true}"#)
+            ),
+            Some(true)
+        );
+        assert_eq!(
+            yank_change(&unyanked, &line(r#"{"name":"serde","vers":"1.0.0"}"#)),
+            None
+        );
+        assert_eq!(
+            yank_change(
+                &unyanked,
+                &line(r#"{"name":"serde","vers":"1.0.0","yanked":true,"v":2}"#)
+            ),
+            None
+        );
+        assert_eq!(
+            yank_change(
+                &line(r#"{"name":"serde","vers":"1.0.0","yanked":false,"v":1}"#),
+                &line(r#"{"name":"serde","vers":"1.0.0","yanked":true,"v":2}"#)
+            ),
+            None
+        );
+        assert_eq!(yank_change(&unyanked, &line("[]")), None);
+    }
+
+    #[test]
+    fn keeps_pure_adds() {
+        let changes = [
+            added(index_line("serde", "1.0.0", false)),
+            added(index_line("serde", "1.0.1", false)),
+        ];
+
+        assert_eq!(
+            actions(&changes),
+            HashSet::from([
+                DiffAction::Add(change("serde", "1.0.0")),
+                DiffAction::Add(change("serde", "1.0.1")),
+            ])
+        );
+    }
+
+    #[test]
+    fn keeps_pure_removes() {
+        let changes = [
+            removed(index_line("serde", "1.0.0", false)),
+            LineChange {
+                path: index_path("tokio"),
+                ..removed(index_line("tokio", "1.0.0", false))
+            },
+        ];
+
+        assert_eq!(
+            actions(&changes),
+            HashSet::from([
+                DiffAction::Remove(change("serde", "1.0.0")),
+                DiffAction::Remove(change("tokio", "1.0.0")),
+            ])
+        );
+    }
+
+    /// Valid lines mixed with one that isn't JSON and one without a name.
+    fn mixed_lines() -> [LineChange; 4] {
+        [
+            added(index_line("serde", "1.0.1", false)),
+            added("not json".to_string()),
+            removed(r#"{"vers":"1.0.0"}"#.to_string()),
+            removed(index_line("serde", "1.0.0", false)),
+        ]
+    }
+
+    #[test]
+    fn skips_lines_it_cannot_parse() {
+        assert_eq!(
+            diff_actions(&mixed_lines(), DiffErrorMode::Skip).unwrap(),
+            HashSet::from([DiffAction::Update(change("serde", "1.0.1"))])
+        );
+    }
+
+    #[test]
+    fn fails_on_lines_it_cannot_parse() {
+        let [valid, not_json, nameless, _] = mixed_lines();
+
+        let result = diff_actions(&[valid.clone(), not_json], DiffErrorMode::Fail);
+        assert!(
+            matches!(result, Err(GitError::JsonError(_))),
+            "{:?}",
+            result
+        );
+
+        let result = diff_actions(&[valid, nameless], DiffErrorMode::Fail);
+        assert!(
+            matches!(result, Err(GitError::DiffContentError(_))),
+            "{:?}",
+            result
+        );
+    }
+}
@@ -1,21 +1,48 @@
 use std::{
+    borrow::Cow,
     collections::HashSet,
-    path::PathBuf,
+    ffi::{OsStr, OsString},
+    fmt,
+    path::{Path, PathBuf},
     process::{ExitStatus, Stdio},
+    sync::Arc,
+    time::Duration,
 };
 
+use chrono::{DateTime, Utc};
 use gitpatch::{ParseError, Patch};
-use serde_json::Value;
+use serde::Deserialize;
 use tokio::{
-    io::{AsyncBufReadExt, BufReader},
+    io::{AsyncBufReadExt, AsyncRead, BufReader},
     process::Command,
+    sync::{OnceCell, Semaphore},
 };
-use tracing::{instrument, log};
+use tokio_util::sync::CancellationToken;
+use tracing::{Instrument, instrument, log};
+
+use crate::crates_index::{self, DiffAction, DiffErrorMode};
 
 #[derive(Debug)]
 pub enum GitError {
     CommandError(std::io::Error),
     DiffParseError(String),
+    /// A changed line couldn't be interpreted, see [`DiffErrorMode`].
+    DiffContentError(String),
+    /// A changed line isn't valid JSON, see [`DiffErrorMode`].
+    JsonError(serde_json::Error),
+    /// The git command didn't finish in time and was killed.
+    Timeout(Duration),
+    /// The git command was killed because the service was cancelled.
+    Cancelled,
+    /// The git command exited unsuccessfully, `stderr` usually says why.
+    Failed {
+        command: String,
+        status: ExitStatus,
+        stderr: String,
+    },
+    /// An in-process operation of [`crate::git2_backend::Git2Backend`] failed.
+    #[cfg(feature = "libgit2")]
+    Libgit2(git2::Error),
 }
 
 impl From<std::io::Error> for GitError {
@@ -24,201 +51,2786 @@ impl From<std::io::Error> for GitError {
     }
 }
 
+impl From<serde_json::Error> for GitError {
+    fn from(err: serde_json::Error) -> Self {
+        GitError::JsonError(err)
+    }
+}
+
+#[cfg(feature = "libgit2")]
+impl From<git2::Error> for GitError {
+    fn from(err: git2::Error) -> Self {
+        GitError::Libgit2(err)
+    }
+}
+
 impl<'a> From<ParseError<'a>> for GitError {
     fn from(value: ParseError<'a>) -> Self {
         GitError::DiffParseError(value.to_string())
     }
 }
 
+/// Environment variable the askpass helper reads the token from, so the token
+/// never shows up in the command line or the remote url.
+const ASKPASS_TOKEN_ENV: &str = "POLL_GIT_TOKEN";
+
+/// `GIT_ASKPASS` helper handing out the token, in a private temporary directory that is
+/// removed once dropped.
+#[derive(Debug)]
+struct AskpassScript {
+    dir: PathBuf,
+}
+
+impl AskpassScript {
+    async fn create() -> Result<Self, std::io::Error> {
+        use tokio::io::AsyncWriteExt;
+
+        // only accessible by us, creating it fails instead of reusing an existing directory
+        let dir =
+            std::env::temp_dir().join(format!("poll-git-askpass-{:016x}", rand::random::<u64>()));
+        tokio::fs::DirBuilder::new()
+            .mode(0o700)
+            .create(&dir)
+            .await?;
+        let script = Self { dir };
+
+        let mut file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o700)
+            .open(script.path())
+            .await?;
+        file.write_all(
+            format!(
+                "#!/bin/sh\ncase \"$1\" in\n    Username*) echo x-access-token ;;\n    *) echo \"${}\" ;;\nesac\n",
+                ASKPASS_TOKEN_ENV
+            )
+            .as_bytes(),
+        )
+        .await?;
+        file.flush().await?;
+
+        Ok(script)
+    }
+
+    fn path(&self) -> PathBuf {
+        self.dir.join("askpass.sh")
+    }
+}
+
+impl Drop for AskpassScript {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_dir_all(&self.dir) {
+            log::warn!("Failed to remove {}: {}", self.dir.display(), e);
+        }
+    }
+}
+
+/// Credentials used when talking to the remote.
+#[derive(Clone, Default)]
+pub enum AuthConfig {
+    #[default]
+    None,
+    /// Token for https remotes, handed to git through `GIT_ASKPASS`.
+    HttpsToken(String),
+    /// Options for ssh remotes, handed to git through `GIT_SSH_COMMAND`.
+    Ssh(SshOptions),
+}
+
+impl fmt::Debug for AuthConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthConfig::None => write!(f, "None"),
+            AuthConfig::HttpsToken(_) => write!(f, "HttpsToken(***)"),
+            AuthConfig::Ssh(options) => f.debug_tuple("Ssh").field(options).finish(),
+        }
+    }
+}
+
+/// Options of the `ssh` command git connects to ssh remotes with.
+///
+/// Host aliases of the ssh config keep working, the options only add to it. The libgit2
+/// backend only uses the identity file.
+#[derive(Debug, Clone, Default)]
+pub struct SshOptions {
+    /// Private key, the only one offered to the server.
+    pub identity_file: Option<PathBuf>,
+    /// Port for `git@host:path` urls, which can't contain one.
+    pub port: Option<u16>,
+    /// Known hosts file used instead of `~/.ssh/known_hosts`.
+    pub known_hosts: Option<PathBuf>,
+}
+
+impl SshOptions {
+    pub fn with_identity_file(mut self, path: PathBuf) -> Self {
+        self.identity_file = Some(path);
+        self
+    }
+
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.port = Some(port);
+        self
+    }
+
+    pub fn with_known_hosts(mut self, path: PathBuf) -> Self {
+        self.known_hosts = Some(path);
+        self
+    }
+
+    /// Value of `GIT_SSH_COMMAND` passing these options to `ssh`.
+    pub fn command(&self) -> OsString {
+        let mut command = OsString::from("ssh");
+        if let Some(identity_file) = &self.identity_file {
+            command.push(" -o IdentitiesOnly=yes -i ");
+            command.push(shell_quote(identity_file.as_os_str()));
+        }
+        if let Some(port) = self.port {
+            command.push(format!(" -p {}", port));
+        }
+        if let Some(known_hosts) = &self.known_hosts {
+            command.push(" -o UserKnownHostsFile=");
+            command.push(shell_quote(known_hosts.as_os_str()));
+        }
+
+        command
+    }
+}
+
+/// Quotes `value` for `sh`, which git runs `GIT_SSH_COMMAND` with.
+fn shell_quote(value: &OsStr) -> OsString {
+    use std::os::unix::ffi::{OsStrExt, OsStringExt};
+
+    let mut quoted = vec![b'\''];
+    for &byte in value.as_bytes() {
+        match byte {
+            b'\'' => quoted.extend_from_slice(b"'\\''"),
+            byte => quoted.push(byte),
+        }
+    }
+    quoted.push(b'\'');
+
+    OsString::from_vec(quoted)
+}
+
+impl AuthConfig {
+    fn secret(&self) -> Option<&str> {
+        match self {
+            AuthConfig::HttpsToken(token) if !token.is_empty() => Some(token),
+            _ => None,
+        }
+    }
+}
+
+/// Replaces every occurrence of `secret` in `line`.
+fn redact(line: &str, secret: Option<&str>) -> String {
+    match secret {
+        Some(secret) => line.replace(secret, "***"),
+        None => line.to_string(),
+    }
+}
+
+/// Objects a partial clone leaves out, git fetches them on demand when a diff needs them.
+///
+/// The libgit2 backend doesn't support partial clones and always clones everything.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CloneFilter {
+    /// A full clone.
+    None,
+    /// Without file contents (`--filter=blob:none`).
+    #[default]
+    BlobNone,
+    /// Without files larger than the given number of bytes (`--filter=blob:limit=<n>`).
+    BlobLimit(u64),
+    /// Without trees and file contents (`--filter=tree:0`), the smallest clone but every
+    /// diff fetches the trees of both commits.
+    TreeZero,
+}
+
+impl CloneFilter {
+    /// Argument of `git clone`, `None` for a full clone.
+    pub fn arg(self) -> Option<String> {
+        match self {
+            CloneFilter::None => None,
+            CloneFilter::BlobNone => Some("--filter=blob:none".to_string()),
+            CloneFilter::BlobLimit(limit) => Some(format!("--filter=blob:limit={}", limit)),
+            CloneFilter::TreeZero => Some("--filter=tree:0".to_string()),
+        }
+    }
+}
+
+/// Options used by [`GitService::clone_repository`].
+#[derive(Debug, Clone)]
+pub struct CloneOptions {
+    /// Only clone the last `n` commits (`--depth n`).
+    pub clone_depth: Option<u32>,
+    pub filter: CloneFilter,
+    /// Clone without a working tree (`--bare`), on by default. A working tree stays at the
+    /// commit it was cloned at, polling only fetches.
+    pub bare: bool,
+}
+
+impl Default for CloneOptions {
+    fn default() -> Self {
+        Self {
+            clone_depth: None,
+            filter: CloneFilter::default(),
+            bare: true,
+        }
+    }
+}
+
+/// Number of commits a shallow clone is deepened by when a commit is missing.
+const DEEPEN_STEP: u32 = 50;
+
+pub const DEFAULT_COMMAND_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Retries of network commands (`clone` and `fetch`) that failed with a transient error.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Retries after the first attempt, `0` disables retrying.
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled on every further retry.
+    pub base_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            base_delay: Duration::from_secs(1),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Delay before retry number `retry` (starting at 1), with up to 50% random jitter so
+    /// repositories failing together don't retry in lockstep.
+    fn delay(&self, retry: u32) -> Duration {
+        let delay = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(retry.saturating_sub(1)));
+
+        delay.mul_f64(rand::random_range(0.5..=1.0))
+    }
+}
+
+/// Whether a failed network command is worth retrying, judged by its stderr.
+///
+/// Auth failures and unknown repositories are never retried, as retrying can't fix them.
+/// Rate limiting (HTTP 429) is, it passes once the host stops throttling.
+fn is_transient_failure(stderr: &str) -> bool {
+    const PERMANENT: &[&str] = &[
+        "authentication failed",
+        "could not read username",
+        "could not read password",
+        "permission denied",
+        "repository not found",
+        "does not appear to be a git repository",
+        "the requested url returned error: 400",
+        "the requested url returned error: 401",
+        "the requested url returned error: 403",
+        "the requested url returned error: 404",
+        "couldn't find remote ref",
+    ];
+    const TRANSIENT: &[&str] = &[
+        "could not resolve host",
+        "temporary failure in name resolution",
+        "failed to connect",
+        "connection timed out",
+        "operation timed out",
+        "connection reset",
+        "connection refused",
+        "the remote end hung up unexpectedly",
+        "early eof",
+        "rpc failed",
+        "the requested url returned error: 429",
+        "the requested url returned error: 5",
+        "gnutls recv error",
+        "ssl_read",
+    ];
+
+    let stderr = stderr.to_lowercase();
+
+    !PERMANENT.iter().any(|marker| stderr.contains(marker))
+        && TRANSIENT.iter().any(|marker| stderr.contains(marker))
+}
+
+/// Captured result of a command run through [`GitService::call_command`].
+#[derive(Debug)]
+pub struct CommandOutput {
+    pub status: ExitStatus,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+/// Reads `stream` to the end, calling `on_line` for every line, and returns everything read.
+async fn read_stream<R>(stream: R, mut on_line: impl FnMut(&str)) -> Vec<u8>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut reader = BufReader::new(stream);
+    let mut output = Vec::new();
+    let mut line = Vec::new();
+
+    loop {
+        line.clear();
+        match reader.read_until(b'\n', &mut line).await {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {
+                on_line(String::from_utf8_lossy(&line).trim_end_matches(['\r', '\n']));
+                output.extend_from_slice(&line);
+            }
+        }
+    }
+
+    output
+}
+
+/// Parses the output of `git diff` file by file.
+///
+/// `Patch::from_multiple` looks ahead across file boundaries for pure renames and would
+/// swallow every file diffed before one, so each file is parsed on its own. Files without
+/// hunks that gitpatch doesn't understand, e.g. mode changes, are skipped.
+fn parse_patches(diff: &str) -> Result<Vec<Patch<'_>>, GitError> {
+    let mut starts: Vec<usize> = diff
+        .match_indices("\ndiff --git ")
+        .map(|(i, _)| i + 1)
+        .collect();
+    if diff.starts_with("diff --git ") {
+        starts.insert(0, 0);
+    }
+
+    let mut patches = Vec::with_capacity(starts.len());
+    for (i, &start) in starts.iter().enumerate() {
+        let end = starts.get(i + 1).copied().unwrap_or(diff.len());
+        let file = &diff[start..end];
+
+        match Patch::from_single(file) {
+            Ok(patch) => patches.push(patch),
+            Err(_) if !file.contains("\n@@ ") => {
+                log::debug!(
+                    "Skipping diff without changes: {}",
+                    file.lines().next().unwrap_or("")
+                );
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok(patches)
+}
+
+/// `diff` without its `\ No newline at end of file` markers.
+///
+/// gitpatch panics on a marker between the removed and the added lines of a hunk. The
+/// marker isn't a line of the file and no other line of a diff starts with `\`, so
+/// dropping them loses nothing.
+fn strip_missing_newline_markers(diff: &str) -> Cow<'_, str> {
+    if !diff.starts_with('\\') && !diff.contains("\n\\") {
+        return Cow::Borrowed(diff);
+    }
+
+    Cow::Owned(
+        diff.split_inclusive('\n')
+            .filter(|line| !line.starts_with('\\'))
+            .collect(),
+    )
+}
+
+/// Whether `patch` moves a file, as detected by `git diff --find-renames`.
+fn is_rename(patch: &Patch) -> bool {
+    const NULL_PATH: &str = "/dev/null";
+
+    patch.old.path != patch.new.path && patch.old.path != NULL_PATH && patch.new.path != NULL_PATH
+}
+
 #[derive(Debug)]
 pub struct GitService {
     repository_path: PathBuf,
+    auth: AuthConfig,
+    command_timeout: Duration,
+    diff_error_mode: DiffErrorMode,
+    path_filters: Vec<String>,
+    /// Passed as `--diff-algorithm`, git's default if unset.
+    diff_algorithm: Option<DiffAlgorithm>,
+    git_binary: PathBuf,
+    /// Remote that is fetched, `origin` unless configured otherwise.
+    remote: String,
+    retry_policy: RetryPolicy,
+    cancellation_token: CancellationToken,
+    /// Bounds the number of git commands running at once, shared between services.
+    command_permits: Option<Arc<Semaphore>>,
+    /// Written on the first command that needs it, see [`GitService::auth_envs`].
+    askpass: OnceCell<AskpassScript>,
 }
 
-impl<'a> GitService {
+/// Executable used when no git binary is configured, looked up in `PATH`.
+pub const DEFAULT_GIT_BINARY: &str = "git";
+
+/// Remote created by `git clone`.
+pub const DEFAULT_REMOTE: &str = "origin";
+
+impl GitService {
     pub fn new(repository_path: PathBuf) -> Self {
-        Self { repository_path }
+        Self::with_auth(repository_path, AuthConfig::None)
+    }
+
+    pub fn with_auth(repository_path: PathBuf, auth: AuthConfig) -> Self {
+        Self {
+            repository_path,
+            auth,
+            command_timeout: DEFAULT_COMMAND_TIMEOUT,
+            diff_error_mode: DiffErrorMode::default(),
+            path_filters: Vec::new(),
+            diff_algorithm: None,
+            git_binary: PathBuf::from(DEFAULT_GIT_BINARY),
+            remote: DEFAULT_REMOTE.to_string(),
+            retry_policy: RetryPolicy::default(),
+            cancellation_token: CancellationToken::new(),
+            command_permits: None,
+            askpass: OnceCell::new(),
+        }
+    }
+
+    pub fn with_command_timeout(mut self, command_timeout: Duration) -> Self {
+        self.command_timeout = command_timeout;
+        self
+    }
+
+    pub fn with_diff_error_mode(mut self, diff_error_mode: DiffErrorMode) -> Self {
+        self.diff_error_mode = diff_error_mode;
+        self
+    }
+
+    /// Restricts diffs to files matching one of the pathspecs, all files if empty.
+    pub fn with_path_filters(mut self, path_filters: Vec<String>) -> Self {
+        self.path_filters = path_filters;
+        self
+    }
+
+    pub fn with_diff_algorithm(mut self, diff_algorithm: DiffAlgorithm) -> Self {
+        self.diff_algorithm = Some(diff_algorithm);
+        self
+    }
+
+    /// Runs `git_binary` instead of `git` from `PATH`.
+    pub fn with_git_binary(mut self, git_binary: PathBuf) -> Self {
+        self.git_binary = git_binary;
+        self
+    }
+
+    /// Fetches `remote` instead of `origin`.
+    pub fn with_remote(mut self, remote: String) -> Self {
+        self.remote = remote;
+        self
+    }
+
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Kills running git commands and fails new ones with [`GitError::Cancelled`] once
+    /// `cancellation_token` is cancelled.
+    pub fn with_cancellation_token(mut self, cancellation_token: CancellationToken) -> Self {
+        self.cancellation_token = cancellation_token;
+        self
+    }
+
+    /// Only runs a git command while holding a permit of `command_permits`, share the
+    /// semaphore between services to limit their concurrent git commands.
+    pub fn with_command_permits(mut self, command_permits: Arc<Semaphore>) -> Self {
+        self.command_permits = Some(command_permits);
+        self
+    }
+
+    /// `git diff` arguments followed by the path filters.
+    fn diff_args<'a>(&'a self, args: &[&'a str]) -> Vec<&'a str> {
+        let mut args = args.to_vec();
+        if let Some(diff_algorithm) = self.diff_algorithm {
+            // right after the subcommand, before the revisions
+            args.insert(1, diff_algorithm.arg());
+        }
+        if !self.path_filters.is_empty() {
+            args.push("--");
+            args.extend(self.path_filters.iter().map(String::as_str));
+        }
+
+        args
+    }
+
+    /// Environment variables that have to be set on a git command to use the credentials.
+    async fn auth_envs(&self) -> Result<Vec<(&'static str, OsString)>, std::io::Error> {
+        let envs = match &self.auth {
+            AuthConfig::None => Vec::new(),
+            AuthConfig::HttpsToken(token) => {
+                let askpass = self.askpass.get_or_try_init(AskpassScript::create).await?;
+
+                vec![
+                    ("GIT_ASKPASS", askpass.path().into()),
+                    ("GIT_TERMINAL_PROMPT", "0".into()),
+                    (ASKPASS_TOKEN_ENV, token.into()),
+                ]
+            }
+            AuthConfig::Ssh(options) => vec![("GIT_SSH_COMMAND", options.command())],
+        };
+
+        Ok(envs)
     }
 
     #[instrument(skip(self))]
     async fn call_command(
         &self,
-        program: &str,
+        program: &Path,
         args: &[&str],
         run_in_parent: bool,
-    ) -> Result<ExitStatus, std::io::Error> {
-        let program = program.to_string();
+    ) -> Result<CommandOutput, GitError> {
+        if self.cancellation_token.is_cancelled() {
+            return Err(GitError::Cancelled);
+        }
 
-        let mut child = Command::new(program.clone().as_str())
+        // released when dropped, on every return below
+        let _permit = match &self.command_permits {
+            Some(permits) => tokio::select! {
+                permit = permits.clone().acquire_owned() => Some(
+                    permit.map_err(|_| std::io::Error::other("git command semaphore was closed"))?,
+                ),
+                _ = self.cancellation_token.cancelled() => return Err(GitError::Cancelled),
+            },
+            None => None,
+        };
+
+        let mut child = Command::new(program)
             .args(args)
+            .envs(self.auth_envs().await?)
             .current_dir(if run_in_parent {
-                self.repository_path.parent().unwrap()
+                // a relative path without directories has an empty parent
+                match self.repository_path.parent() {
+                    Some(parent) if !parent.as_os_str().is_empty() => parent,
+                    _ => Path::new("."),
+                }
             } else {
                 &self.repository_path
             })
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
+            .kill_on_drop(true)
             .spawn()?;
 
         let stdout = child.stdout.take().unwrap();
         let stderr = child.stderr.take().unwrap();
 
         // stdout -> debug
-        let p = program.clone();
-        let stdout_task = tokio::spawn(async move {
-            let mut lines = BufReader::new(stdout).lines();
-            while let Ok(Some(line)) = lines.next_line().await {
-                log::debug!("{}: {}", p.as_str(), line);
-            }
-        });
+        let p = program.display().to_string();
+        let secret = self.auth.secret().map(str::to_string);
+        let stdout_task = tokio::spawn(
+            read_stream(stdout, move |line| {
+                log::debug!("{}: {}", p.as_str(), redact(line, secret.as_deref()));
+            })
+            .in_current_span(),
+        );
 
         // stderr -> error
-        let p = program.clone();
-        let stderr_task = tokio::spawn(async move {
-            let mut lines = BufReader::new(stderr).lines();
-            while let Ok(Some(line)) = lines.next_line().await {
-                log::error!("{}: {}", p.as_str(), line);
+        let p = program.display().to_string();
+        let secret = self.auth.secret().map(str::to_string);
+        let stderr_task = tokio::spawn(
+            read_stream(stderr, move |line| {
+                log::error!("{}: {}", p.as_str(), redact(line, secret.as_deref()));
+            })
+            .in_current_span(),
+        );
+
+        let status = tokio::select! {
+            status = tokio::time::timeout(self.command_timeout, child.wait()) => match status {
+                Ok(status) => status?,
+                Err(_) => {
+                    log::error!(
+                        "{} did not finish within {:?}, killing it",
+                        program.display(),
+                        self.command_timeout
+                    );
+                    child.kill().await?;
+                    return Err(GitError::Timeout(self.command_timeout));
+                }
+            },
+            _ = self.cancellation_token.cancelled() => {
+                log::info!("{} was cancelled, killing it", program.display());
+                child.kill().await?;
+                return Err(GitError::Cancelled);
             }
-        });
+        };
+
+        let stdout = stdout_task.await.map_err(std::io::Error::from)?;
+        let stderr = stderr_task.await.map_err(std::io::Error::from)?;
+
+        Ok(CommandOutput {
+            status,
+            stdout: String::from_utf8_lossy(&stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&stderr).into_owned(),
+        })
+    }
 
-        let status = child.wait().await?;
+    /// Turns an unsuccessful exit of `command` into [`GitError::Failed`] with its stderr.
+    fn check_status(&self, command: &str, out: &CommandOutput) -> Result<(), GitError> {
+        if out.status.success() {
+            return Ok(());
+        }
+
+        Err(GitError::Failed {
+            command: command.to_string(),
+            status: out.status,
+            stderr: redact(out.stderr.trim(), self.auth.secret()),
+        })
+    }
+
+    /// Runs a network command, retrying transient failures according to the retry policy.
+    ///
+    /// Returns the output of the last attempt, a timeout of the last attempt is returned as
+    /// [`GitError::Timeout`]. `target` is removed before every retry, a killed clone leaves
+    /// its partial directory behind.
+    async fn call_with_retry(
+        &self,
+        args: &[&str],
+        run_in_parent: bool,
+        target: Option<&Path>,
+    ) -> Result<CommandOutput, GitError> {
+        let mut retry = 0;
+
+        loop {
+            let result = self
+                .call_command(&self.git_binary, args, run_in_parent)
+                .await;
+
+            let transient = match &result {
+                Ok(out) => !out.status.success() && is_transient_failure(&out.stderr),
+                Err(GitError::Timeout(_)) => true,
+                Err(_) => false,
+            };
+            if !transient || retry >= self.retry_policy.max_retries {
+                return result;
+            }
+
+            retry += 1;
+            let delay = self.retry_policy.delay(retry);
+            log::warn!(
+                "git {} failed with a transient error, retry {}/{} in {:?}",
+                args.first().unwrap_or(&""),
+                retry,
+                self.retry_policy.max_retries,
+                delay
+            );
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = self.cancellation_token.cancelled() => return Err(GitError::Cancelled),
+            }
+
+            if let Some(target) = target {
+                match tokio::fs::remove_dir_all(target).await {
+                    Ok(()) => log::info!("Removed {} of the failed attempt", target.display()),
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
+    }
+
+    pub async fn clone_repository(
+        &self,
+        git_url: &str,
+        options: &CloneOptions,
+    ) -> Result<(), GitError> {
+        let depth = options.clone_depth.map(|depth| depth.to_string());
+        let filter = options.filter.arg();
+
+        let mut args = vec!["clone"];
+        if let Some(filter) = &filter {
+            args.push(filter);
+        }
+        if options.bare {
+            args.push("--bare");
+        }
+        if let Some(depth) = &depth {
+            args.extend(["--depth", depth]);
+        }
+        // cloned from the parent directory, so only the last component is passed on
+        let dir_name = self
+            .repository_path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "Repository path {} doesn't end in a valid directory name",
+                        self.repository_path.display()
+                    ),
+                )
+            })?;
+        args.extend([git_url, dir_name]);
 
-        stdout_task.await?;
-        stderr_task.await?;
+        let out = self
+            .call_with_retry(&args, true, Some(&self.repository_path))
+            .await?;
+
+        self.check_status("clone", &out)
+    }
+
+    /// Checks the repository with `git fsck --connectivity-only`, a missing repository
+    /// directory counts as invalid.
+    pub async fn verify(&self) -> Result<bool, GitError> {
+        if !tokio::fs::metadata(&self.repository_path)
+            .await
+            .is_ok_and(|meta| meta.is_dir())
+        {
+            return Ok(false);
+        }
+
+        let out = self
+            .call_command(&self.git_binary, &["fsck", "--connectivity-only"], false)
+            .await?;
 
-        Ok(status)
+        Ok(out.status.success())
     }
 
-    pub async fn clone_repository(&self, git_url: &str) -> Result<(), GitError> {
-        let status = self
+    /// Whether the repository path is a git repository of its own, an interrupted clone
+    /// or a directory inside another repository isn't.
+    ///
+    /// Fails if git can't tell, e.g. for a repository of another user.
+    pub async fn is_repository(&self) -> Result<bool, GitError> {
+        if !tokio::fs::metadata(&self.repository_path)
+            .await
+            .is_ok_and(|meta| meta.is_dir())
+        {
+            return Ok(false);
+        }
+
+        let out = self
             .call_command(
-                "git",
-                &[
-                    "clone",
-                    "--filter=blob:none",
-                    "--bare",
-                    &git_url,
-                    self.repository_path.file_name().unwrap().to_str().unwrap(),
-                ],
-                true,
+                &self.git_binary,
+                &["rev-parse", "--absolute-git-dir"],
+                false,
             )
             .await?;
+        if !out.status.success() {
+            if out.stderr.contains("not a git repository") {
+                return Ok(false);
+            }
 
-        if status.success() {
-            Ok(())
-        } else {
-            Err(GitError::CommandError(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!("Git clone command failed with exit status: {}", status),
-            )))
+            return self.check_status("rev-parse", &out).map(|()| false);
         }
-    }
 
-    pub async fn fetch(&self) -> Result<(), GitError> {
-        let status = self.call_command("git", &["fetch", "--all"], false).await?;
+        // rev-parse also succeeds in a subdirectory of a repository, so compare the git dir
+        // with the one of a bare clone and of a working tree
+        let repository_path = tokio::fs::canonicalize(&self.repository_path).await?;
+        Ok(tokio::fs::canonicalize(out.stdout.trim())
+            .await
+            .is_ok_and(|git_dir| {
+                git_dir == repository_path || git_dir == repository_path.join(".git")
+            }))
+    }
 
-        if status.success() {
-            Ok(())
-        } else {
-            Err(GitError::CommandError(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!("Git fetch command failed with exit status: {}", status),
-            )))
+    /// Deletes the repository directory and clones it again.
+    pub async fn reclone(&self, git_url: &str, options: &CloneOptions) -> Result<(), GitError> {
+        match tokio::fs::remove_dir_all(&self.repository_path).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
         }
+
+        self.clone_repository(git_url, options).await
     }
 
-    pub async fn get_current_commit_hash_from_rev(
-        &self,
-        rev: &str,
-    ) -> Result<Option<String>, GitError> {
-        let out = Command::new("git")
-            .arg("rev-parse")
-            .arg(rev)
-            .current_dir(&self.repository_path)
-            .output()
+    /// Fetches the remote's `HEAD` into `FETCH_HEAD`, returns the commit it points to.
+    ///
+    /// Only the remote's `HEAD` is fetched, so `FETCH_HEAD` always points to its tip, also
+    /// in a working tree that fetches all branches by default.
+    /// An empty remote has no `HEAD` to fetch, `FETCH_HEAD` is emptied instead.
+    #[instrument(skip(self))]
+    pub async fn fetch(&self) -> Result<Option<String>, GitError> {
+        let out = self
+            .call_with_retry(&["fetch", &self.remote, "HEAD"], false, None)
             .await?;
 
-        if out.status.success() {
-            let stdout = String::from_utf8_lossy(&out.stdout);
-            Ok(Some(stdout.trim().to_string()))
-        } else {
-            Ok(None)
+        if self.is_empty_remote(&out).await? {
+            tokio::fs::write(self.git_path("FETCH_HEAD").await?, "").await?;
+            return Ok(None);
         }
-    }
+        self.check_status("fetch", &out)?;
 
-    pub async fn get_current_commit_hash_from_fetch_head(
-        &self,
-    ) -> Result<Option<String>, GitError> {
-        Ok(self.get_current_commit_hash_from_rev("FETCH_HEAD").await?)
+        self.get_current_commit_hash_from_fetch_head().await
     }
 
-    pub async fn diff_commits_name_only(
-        &self,
-        c1: &str,
-        c2: &str,
-    ) -> Result<Vec<String>, GitError> {
-        let out = Command::new("git")
-            .args(&["diff", "--name-only", c1, c2])
-            .current_dir(&self.repository_path)
-            .output()
+    /// Fetches only `branch` of the remote into `refs/remotes/<remote>/<branch>`, returns
+    /// the commit it points to.
+    ///
+    /// An empty remote has no branch to fetch and leaves the refs untouched.
+    pub async fn fetch_branch(&self, branch: &str) -> Result<Option<String>, GitError> {
+        let refspec = format!("+refs/heads/{1}:refs/remotes/{0}/{1}", self.remote, branch);
+        let out = self
+            .call_with_retry(&["fetch", &self.remote, &refspec], false, None)
             .await?;
 
-        if out.status.success() {
-            let stdout = String::from_utf8_lossy(&out.stdout);
-            let files: Vec<String> = stdout.lines().map(|line| line.to_string()).collect();
-            Ok(files)
-        } else {
-            Err(GitError::CommandError(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!("Git diff command failed with exit status: {}", out.status),
-            )))
+        if !self.is_empty_remote(&out).await? {
+            self.check_status("fetch", &out)?;
         }
+
+        self.get_current_commit_hash_from_rev(&format!("{}/{}", self.remote, branch))
+            .await
     }
 
-    pub async fn diff_commits(&self, c1: &str, c2: &str) -> Result<HashSet<DiffAction>, GitError> {
-        let out = Command::new("git")
-            .args(&["diff", c1, c2])
-            .current_dir(&self.repository_path)
-            .output()
+    /// Path of `file` in the git dir, which is `.git` of a working tree.
+    async fn git_path(&self, file: &str) -> Result<PathBuf, GitError> {
+        let out = self
+            .call_command(&self.git_binary, &["rev-parse", "--git-path", file], false)
             .await?;
 
-        if !out.status.success() {
-            return Err(GitError::CommandError(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!("Git diff command failed with exit status: {}", out.status),
-            )));
+        self.check_status("rev-parse", &out)?;
+
+        // relative to the repository path, which commands run in
+        Ok(self.repository_path.join(out.stdout.trim()))
+    }
+
+    /// Whether a fetch failed only because the remote has no refs at all yet.
+    async fn is_empty_remote(&self, out: &CommandOutput) -> Result<bool, GitError> {
+        if out.status.success() || !out.stderr.contains("couldn't find remote ref") {
+            return Ok(false);
+        }
+
+        let empty = self.list_remote_refs().await?.is_empty();
+        if empty {
+            log::info!("Remote {} has no commits yet", self.remote);
         }
 
-        let stdout = String::from_utf8_lossy(&out.stdout);
-        let patches = Patch::from_multiple(&stdout)?;
+        Ok(empty)
+    }
 
-        let actions = patches
-            .iter()
-            .flat_map(|patch| patch.hunks.iter())
-            .flat_map(|hunk| hunk.lines.iter())
-            .filter_map(|line| match line {
-                // TODO how to handle Update? Remove followed by an Add?
-                gitpatch::Line::Add(raw) => {
-                    let value: Value = serde_json::from_str(&raw).unwrap();
-                    let name = value["name"].as_str().unwrap().to_string();
-                    Some(DiffAction::Add(name))
-                }
-                gitpatch::Line::Remove(raw) => {
-                    let value: Value = serde_json::from_str(&raw).unwrap();
-                    let name = value["name"].as_str().unwrap().to_string();
-                    Some(DiffAction::Remove(name))
-                }
-                gitpatch::Line::Context(_) => None,
-            })
-            .collect::<HashSet<_>>();
+    /// Deepens a shallow clone by `depth` commits.
+    pub async fn deepen(&self, depth: u32) -> Result<(), GitError> {
+        let out = self
+            .call_command(
+                &self.git_binary,
+                &["fetch", &format!("--deepen={}", depth)],
+                false,
+            )
+            .await?;
 
-        Ok(actions)
+        self.check_status("fetch --deepen", &out)
+    }
+
+    pub async fn is_shallow(&self) -> Result<bool, GitError> {
+        let out = self
+            .call_command(
+                &self.git_binary,
+                &["rev-parse", "--is-shallow-repository"],
+                false,
+            )
+            .await?;
+
+        Ok(out.status.success() && out.stdout.trim() == "true")
     }
-}
 
-#[derive(Debug, Hash, PartialEq, Eq)]
-pub enum DiffAction {
-    Add(String),
-    Update(String),
-    Remove(String),
+    pub async fn has_commit(&self, commit: &str) -> Result<bool, GitError> {
+        let out = self
+            .call_command(
+                &self.git_binary,
+                &["cat-file", "-e", &format!("{}^{{commit}}", commit)],
+                false,
+            )
+            .await?;
+
+        Ok(out.status.success())
+    }
+
+    /// Whether `ancestor` is `descendant` or one of its ancestors, an `ancestor` that isn't
+    /// available locally isn't.
+    pub async fn is_ancestor(&self, ancestor: &str, descendant: &str) -> Result<bool, GitError> {
+        self.ensure_commit(ancestor).await?;
+        if !self.has_commit(ancestor).await? {
+            return Ok(false);
+        }
+
+        let out = self
+            .call_command(
+                &self.git_binary,
+                &["merge-base", "--is-ancestor", ancestor, descendant],
+                false,
+            )
+            .await?;
+
+        // exits with 1 if it isn't an ancestor, anything else is an error
+        match out.status.code() {
+            Some(0) => Ok(true),
+            Some(1) => Ok(false),
+            _ => self.check_status("merge-base", &out).map(|()| false),
+        }
+    }
+
+    /// Deepens a shallow clone until `commit` is available locally.
+    ///
+    /// Does nothing for a full clone, a missing commit is reported by the following git command.
+    async fn ensure_commit(&self, commit: &str) -> Result<(), GitError> {
+        while !self.has_commit(commit).await? {
+            if !self.is_shallow().await? {
+                break;
+            }
+
+            log::info!(
+                "Commit {} is not part of the shallow clone, deepening by {}",
+                commit,
+                DEEPEN_STEP
+            );
+            self.deepen(DEEPEN_STEP).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Commit `rev` resolves to, `None` if it doesn't exist like `FETCH_HEAD` of an empty
+    /// repository.
+    pub async fn get_current_commit_hash_from_rev(
+        &self,
+        rev: &str,
+    ) -> Result<Option<String>, GitError> {
+        let commit = format!("{}^{{commit}}", rev);
+        let out = self
+            .call_command(
+                &self.git_binary,
+                &["rev-parse", "--verify", "--quiet", &commit],
+                false,
+            )
+            .await?;
+
+        // `--verify --quiet` exits with 1 for an unknown revision, anything else is an error
+        match out.status.code() {
+            Some(0) => Ok(Some(out.stdout.trim().to_string())),
+            Some(1) => Ok(None),
+            _ => self.check_status("rev-parse", &out).map(|()| None),
+        }
+    }
+
+    #[instrument(skip(self))]
+    pub async fn get_current_commit_hash_from_fetch_head(
+        &self,
+    ) -> Result<Option<String>, GitError> {
+        self.get_current_commit_hash_from_rev("FETCH_HEAD").await
+    }
+
+    /// Branches and tags of the remote.
+    pub async fn list_remote_refs(&self) -> Result<Vec<RemoteRef>, GitError> {
+        let out = self
+            .call_command(&self.git_binary, &["ls-remote", &self.remote], false)
+            .await?;
+
+        self.check_status("ls-remote", &out)?;
+
+        Ok(RemoteRef::parse_ls_remote(&out.stdout))
+    }
+
+    /// Author, timestamp and subject of `rev`.
+    pub async fn get_commit_metadata(&self, rev: &str) -> Result<CommitMeta, GitError> {
+        let out = self
+            .call_command(
+                &self.git_binary,
+                &["show", "-s", "--format=%H%x00%an%x00%ae%x00%ct%x00%s", rev],
+                false,
+            )
+            .await?;
+
+        self.check_status("show", &out)?;
+
+        Ok(CommitMeta::parse(out.stdout.trim_end_matches('\n')))
+    }
+
+    pub async fn diff_commits_name_only(
+        &self,
+        c1: &str,
+        c2: &str,
+    ) -> Result<Vec<String>, GitError> {
+        let out = self
+            .call_command(
+                &self.git_binary,
+                &self.diff_args(&["diff", "--name-only", "--find-renames", c1, c2]),
+                false,
+            )
+            .await?;
+
+        self.check_status("diff", &out)?;
+
+        Ok(out.stdout.lines().map(|line| line.to_string()).collect())
+    }
+
+    /// Lines added and deleted per file between two commits.
+    pub async fn diff_stats(&self, c1: &str, c2: &str) -> Result<Vec<FileStat>, GitError> {
+        let out = self
+            .call_command(
+                &self.git_binary,
+                &self.diff_args(&["diff", "--numstat", "-z", "--find-renames", c1, c2]),
+                false,
+            )
+            .await?;
+
+        self.check_status("diff", &out)?;
+
+        FileStat::parse_numstat(&out.stdout)
+    }
+
+    /// Diffs two commits and returns every added or removed line together with its file.
+    pub async fn diff_commits_raw(&self, c1: &str, c2: &str) -> Result<Vec<LineChange>, GitError> {
+        self.ensure_commit(c1).await?;
+        self.ensure_commit(c2).await?;
+
+        let out = self
+            .call_command(
+                &self.git_binary,
+                &self.diff_args(&["diff", "--no-prefix", "--find-renames", c1, c2]),
+                false,
+            )
+            .await?;
+
+        self.check_status("diff", &out)?;
+
+        let diff = strip_missing_newline_markers(&out.stdout);
+        let patches = parse_patches(&diff)?;
+
+        // renamed files only contribute their changed lines, a pure rename none at all
+        for patch in patches.iter().filter(|patch| is_rename(patch)) {
+            log::debug!("Renamed {} -> {}", patch.old.path, patch.new.path);
+        }
+
+        let changes = patches
+            .iter()
+            .flat_map(|patch| {
+                patch
+                    .hunks
+                    .iter()
+                    .flat_map(|hunk| hunk.lines.iter())
+                    .filter_map(|line| match line {
+                        gitpatch::Line::Add(raw) => Some(LineChange {
+                            path: patch.new.path.to_string(),
+                            kind: ChangeKind::Added,
+                            line: raw.to_string(),
+                        }),
+                        gitpatch::Line::Remove(raw) => Some(LineChange {
+                            path: patch.old.path.to_string(),
+                            kind: ChangeKind::Removed,
+                            line: raw.to_string(),
+                        }),
+                        gitpatch::Line::Context(_) => None,
+                    })
+            })
+            .collect();
+
+        Ok(changes)
+    }
+
+    /// Diffs two commits of the crates.io index, see [`crates_index::diff_actions`].
+    #[instrument(skip(self))]
+    pub async fn diff_commits(&self, c1: &str, c2: &str) -> Result<HashSet<DiffAction>, GitError> {
+        let changes = self.diff_commits_raw(c1, c2).await?;
+
+        crates_index::diff_actions(&changes, self.diff_error_mode)
+    }
+}
+
+/// Algorithm `git diff` pairs up the changed lines with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiffAlgorithm {
+    Myers,
+    Minimal,
+    Patience,
+    Histogram,
+}
+
+impl DiffAlgorithm {
+    fn arg(self) -> &'static str {
+        match self {
+            DiffAlgorithm::Myers => "--diff-algorithm=myers",
+            DiffAlgorithm::Minimal => "--diff-algorithm=minimal",
+            DiffAlgorithm::Patience => "--diff-algorithm=patience",
+            DiffAlgorithm::Histogram => "--diff-algorithm=histogram",
+        }
+    }
+}
+
+/// Implementation of [`GitBackend`] an indexer clones and polls its repository with.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GitBackendKind {
+    /// Runs the `git` executable, see [`GitService`].
+    #[default]
+    Cli,
+    /// Runs in-process through libgit2, see [`crate::git2_backend::Git2Backend`].
+    #[cfg(feature = "libgit2")]
+    Libgit2,
+}
+
+/// Git operations the indexer relies on, implemented by [`GitService`].
+///
+/// Lets the actor run against a mock instead of a real repository.
+#[async_trait::async_trait]
+pub trait GitBackend: Send + Sync {
+    /// Fetches the remote's `HEAD`, returns its commit or `None` for an empty remote.
+    async fn fetch(&self) -> Result<Option<String>, GitError>;
+
+    /// Fetches `branch` of the remote, returns its commit or `None` if it doesn't exist.
+    async fn fetch_branch(&self, branch: &str) -> Result<Option<String>, GitError>;
+
+    async fn get_current_commit_hash_from_fetch_head(&self) -> Result<Option<String>, GitError>;
+
+    async fn get_current_commit_hash_from_rev(&self, rev: &str)
+    -> Result<Option<String>, GitError>;
+
+    async fn diff_commits(&self, c1: &str, c2: &str) -> Result<HashSet<DiffAction>, GitError>;
+
+    async fn diff_stats(&self, c1: &str, c2: &str) -> Result<Vec<FileStat>, GitError>;
+
+    async fn get_commit_metadata(&self, rev: &str) -> Result<CommitMeta, GitError>;
+
+    /// Whether `ancestor` is `descendant` or one of its ancestors.
+    async fn is_ancestor(&self, ancestor: &str, descendant: &str) -> Result<bool, GitError>;
+
+    async fn verify(&self) -> Result<bool, GitError>;
+
+    async fn is_repository(&self) -> Result<bool, GitError>;
+
+    async fn reclone(&self, git_url: &str, options: &CloneOptions) -> Result<(), GitError>;
+}
+
+#[async_trait::async_trait]
+impl GitBackend for GitService {
+    async fn fetch(&self) -> Result<Option<String>, GitError> {
+        GitService::fetch(self).await
+    }
+
+    async fn fetch_branch(&self, branch: &str) -> Result<Option<String>, GitError> {
+        GitService::fetch_branch(self, branch).await
+    }
+
+    async fn get_current_commit_hash_from_rev(
+        &self,
+        rev: &str,
+    ) -> Result<Option<String>, GitError> {
+        GitService::get_current_commit_hash_from_rev(self, rev).await
+    }
+
+    async fn get_current_commit_hash_from_fetch_head(&self) -> Result<Option<String>, GitError> {
+        GitService::get_current_commit_hash_from_fetch_head(self).await
+    }
+
+    async fn diff_commits(&self, c1: &str, c2: &str) -> Result<HashSet<DiffAction>, GitError> {
+        GitService::diff_commits(self, c1, c2).await
+    }
+
+    async fn diff_stats(&self, c1: &str, c2: &str) -> Result<Vec<FileStat>, GitError> {
+        GitService::diff_stats(self, c1, c2).await
+    }
+
+    async fn get_commit_metadata(&self, rev: &str) -> Result<CommitMeta, GitError> {
+        GitService::get_commit_metadata(self, rev).await
+    }
+
+    async fn is_ancestor(&self, ancestor: &str, descendant: &str) -> Result<bool, GitError> {
+        GitService::is_ancestor(self, ancestor, descendant).await
+    }
+
+    async fn verify(&self) -> Result<bool, GitError> {
+        GitService::verify(self).await
+    }
+
+    async fn is_repository(&self) -> Result<bool, GitError> {
+        GitService::is_repository(self).await
+    }
+
+    async fn reclone(&self, git_url: &str, options: &CloneOptions) -> Result<(), GitError> {
+        GitService::reclone(self, git_url, options).await
+    }
+}
+
+#[async_trait::async_trait]
+impl<T: GitBackend + ?Sized> GitBackend for Arc<T> {
+    async fn fetch(&self) -> Result<Option<String>, GitError> {
+        (**self).fetch().await
+    }
+
+    async fn fetch_branch(&self, branch: &str) -> Result<Option<String>, GitError> {
+        (**self).fetch_branch(branch).await
+    }
+
+    async fn get_current_commit_hash_from_rev(
+        &self,
+        rev: &str,
+    ) -> Result<Option<String>, GitError> {
+        (**self).get_current_commit_hash_from_rev(rev).await
+    }
+
+    async fn get_current_commit_hash_from_fetch_head(&self) -> Result<Option<String>, GitError> {
+        (**self).get_current_commit_hash_from_fetch_head().await
+    }
+
+    async fn diff_commits(&self, c1: &str, c2: &str) -> Result<HashSet<DiffAction>, GitError> {
+        (**self).diff_commits(c1, c2).await
+    }
+
+    async fn diff_stats(&self, c1: &str, c2: &str) -> Result<Vec<FileStat>, GitError> {
+        (**self).diff_stats(c1, c2).await
+    }
+
+    async fn get_commit_metadata(&self, rev: &str) -> Result<CommitMeta, GitError> {
+        (**self).get_commit_metadata(rev).await
+    }
+
+    async fn is_ancestor(&self, ancestor: &str, descendant: &str) -> Result<bool, GitError> {
+        (**self).is_ancestor(ancestor, descendant).await
+    }
+
+    async fn verify(&self) -> Result<bool, GitError> {
+        (**self).verify().await
+    }
+
+    async fn is_repository(&self) -> Result<bool, GitError> {
+        (**self).is_repository().await
+    }
+
+    async fn reclone(&self, git_url: &str, options: &CloneOptions) -> Result<(), GitError> {
+        (**self).reclone(git_url, options).await
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RefKind {
+    /// The remote's `HEAD`.
+    Head,
+    Branch,
+    Tag,
+    /// Anything else, e.g. `refs/pull/*`.
+    Other,
+}
+
+/// Changed lines of a single file, see [`GitService::diff_stats`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileStat {
+    /// Path of the file, its new path if it was renamed.
+    pub path: String,
+    /// `None` for binary files.
+    pub added: Option<u64>,
+    /// `None` for binary files.
+    pub deleted: Option<u64>,
+}
+
+impl FileStat {
+    /// Parses the output of `git diff --numstat -z`.
+    ///
+    /// Entries are `<added>\t<deleted>\t<path>\0`, renames leave the path empty and are
+    /// followed by `<old path>\0<new path>\0`. Binary files report `-` for both counts.
+    fn parse_numstat(output: &str) -> Result<Vec<FileStat>, GitError> {
+        let invalid =
+            |entry: &str| GitError::DiffContentError(format!("Invalid numstat entry: {:?}", entry));
+        let count = |value: &str, entry: &str| match value {
+            "-" => Ok(None),
+            value => value.parse().map(Some).map_err(|_| invalid(entry)),
+        };
+
+        let mut stats = Vec::new();
+        let mut fields = output.split('\0');
+
+        while let Some(entry) = fields.next() {
+            if entry.is_empty() {
+                continue;
+            }
+
+            let mut parts = entry.splitn(3, '\t');
+            let (Some(added), Some(deleted), Some(path)) =
+                (parts.next(), parts.next(), parts.next())
+            else {
+                return Err(invalid(entry));
+            };
+
+            let path = if path.is_empty() {
+                // rename, skip the old path
+                let (Some(_), Some(new_path)) = (fields.next(), fields.next()) else {
+                    return Err(invalid(entry));
+                };
+                if new_path.is_empty() {
+                    return Err(invalid(entry));
+                }
+                new_path
+            } else {
+                path
+            };
+
+            stats.push(FileStat {
+                path: path.to_string(),
+                added: count(added, entry)?,
+                deleted: count(deleted, entry)?,
+            });
+        }
+
+        Ok(stats)
+    }
+}
+
+/// A ref of the remote, see [`GitService::list_remote_refs`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoteRef {
+    /// Short name for branches and tags, the full ref name otherwise.
+    pub name: String,
+    pub kind: RefKind,
+    pub hash: String,
+    /// Commit an annotated tag points to, `hash` is the tag object itself.
+    pub peeled: Option<String>,
+}
+
+impl RemoteRef {
+    /// Parses the tab separated `<hash>\t<ref>` lines of `git ls-remote`.
+    ///
+    /// Peeled `^{}` entries are folded into their tag, malformed lines are skipped.
+    fn parse_ls_remote(output: &str) -> Vec<RemoteRef> {
+        let mut refs: Vec<RemoteRef> = Vec::new();
+
+        for line in output.lines() {
+            let Some((hash, full_name)) = line.split_once('\t') else {
+                if !line.trim().is_empty() {
+                    log::warn!("Ignoring malformed ls-remote line: {}", line);
+                }
+                continue;
+            };
+            let (hash, full_name) = (hash.trim(), full_name.trim());
+
+            if let Some(peeled) = full_name.strip_suffix("^{}") {
+                let tag_name = peeled.strip_prefix("refs/tags/").unwrap_or(peeled);
+                match refs
+                    .iter_mut()
+                    .find(|r| r.kind == RefKind::Tag && r.name == tag_name)
+                {
+                    Some(tag) => tag.peeled = Some(hash.to_string()),
+                    None => log::warn!("Peeled ref {} without its tag", full_name),
+                }
+                continue;
+            }
+
+            let (kind, name) = if full_name == "HEAD" {
+                (RefKind::Head, full_name)
+            } else if let Some(branch) = full_name.strip_prefix("refs/heads/") {
+                (RefKind::Branch, branch)
+            } else if let Some(tag) = full_name.strip_prefix("refs/tags/") {
+                (RefKind::Tag, tag)
+            } else {
+                (RefKind::Other, full_name)
+            };
+
+            refs.push(RemoteRef {
+                name: name.to_string(),
+                kind,
+                hash: hash.to_string(),
+                peeled: None,
+            });
+        }
+
+        refs
+    }
+}
+
+/// Metadata of a single commit, see [`GitService::get_commit_metadata`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommitMeta {
+    pub hash: String,
+    pub author_name: String,
+    pub author_email: String,
+    /// `None` if git reported no or an invalid commit timestamp.
+    pub committed_at: Option<DateTime<Utc>>,
+    pub subject: String,
+}
+
+impl CommitMeta {
+    /// Parses the NUL separated output of `git show -s --format=%H%x00%an%x00%ae%x00%ct%x00%s`,
+    /// missing fields are left empty.
+    fn parse(raw: &str) -> Self {
+        let mut fields = raw.split('\0');
+        let mut next = || fields.next().unwrap_or_default().to_string();
+
+        let hash = next();
+        let author_name = next();
+        let author_email = next();
+        let committed_at = next()
+            .parse::<i64>()
+            .ok()
+            .and_then(|seconds| DateTime::from_timestamp(seconds, 0));
+        let subject = next();
+
+        Self {
+            hash,
+            author_name,
+            author_email,
+            committed_at,
+            subject,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    Added,
+    Removed,
+}
+
+/// A single changed line of a diff.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineChange {
+    /// Path of the file relative to the repository root.
+    pub path: String,
+    pub kind: ChangeKind,
+    pub line: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    use crate::crates_index::CrateChange;
+    use crate::test_util::{Remote, TempDir, git, index_line, index_path, script};
+
+    /// Fake git that logs every call to `calls` and fails the first `failures` calls with
+    /// `stderr`.
+    fn flaky_git(dir: &TempDir, failures: usize, stderr: &str) -> PathBuf {
+        script(
+            dir.path(),
+            "git",
+            &format!(
+                "echo \"$@\" >> {0}/calls\n\
+                 if [ \"$(wc -l < {0}/calls)\" -le {1} ]; then echo '{2}' >&2; exit 128; fi",
+                dir.path().display(),
+                failures,
+                stderr
+            ),
+        )
+    }
+
+    fn calls(dir: &TempDir) -> usize {
+        std::fs::read_to_string(dir.path().join("calls"))
+            .unwrap_or_default()
+            .lines()
+            .count()
+    }
+
+    fn retrying_service(dir: &TempDir, git_binary: PathBuf) -> GitService {
+        GitService::new(dir.path().to_path_buf())
+            .with_git_binary(git_binary)
+            .with_retry_policy(RetryPolicy {
+                max_retries: 3,
+                base_delay: Duration::from_millis(1),
+            })
+    }
+
+    const UNRESOLVED_HOST: &str =
+        "fatal: unable to access 'https://example.com/': Could not resolve host: example.com";
+
+    #[test]
+    fn classifies_failures_by_their_stderr() {
+        let url_error = |status| {
+            format!(
+                "fatal: unable to access 'https://example.com/': The requested URL returned error: {}",
+                status
+            )
+        };
+
+        assert!(is_transient_failure(UNRESOLVED_HOST));
+        assert!(is_transient_failure(
+            "error: RPC failed; curl 56 GnuTLS recv error (-9)\nfatal: early EOF"
+        ));
+        assert!(is_transient_failure(&url_error(429)));
+        assert!(is_transient_failure(&url_error(502)));
+        for status in [400, 401, 403, 404] {
+            assert!(!is_transient_failure(&url_error(status)), "{}", status);
+        }
+        assert!(!is_transient_failure(
+            "fatal: Authentication failed for 'https://example.com/'"
+        ));
+        assert!(!is_transient_failure("ERROR: Repository not found."));
+        // unknown failures aren't retried either
+        assert!(!is_transient_failure("fatal: bad object HEAD"));
+    }
+
+    #[tokio::test]
+    async fn retries_transient_failures() {
+        let dir = TempDir::new();
+        let service = retrying_service(&dir, flaky_git(&dir, 2, UNRESOLVED_HOST));
+
+        let out = service
+            .call_with_retry(&["fetch", "origin", "HEAD"], false, None)
+            .await
+            .unwrap();
+
+        assert!(out.status.success());
+        assert_eq!(calls(&dir), 3);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_the_last_retry() {
+        let dir = TempDir::new();
+        let service = retrying_service(&dir, flaky_git(&dir, 10, UNRESOLVED_HOST));
+
+        let out = service
+            .call_with_retry(&["fetch", "origin", "HEAD"], false, None)
+            .await
+            .unwrap();
+
+        assert!(!out.status.success());
+        assert!(out.stderr.contains("Could not resolve host"));
+        assert_eq!(calls(&dir), 4);
+    }
+
+    #[tokio::test]
+    async fn does_not_retry_permanent_failures() {
+        let dir = TempDir::new();
+        let git_binary = flaky_git(
+            &dir,
+            10,
+            "fatal: Authentication failed for 'https://example.com/'",
+        );
+        let service = retrying_service(&dir, git_binary);
+
+        let out = service
+            .call_with_retry(&["fetch", "origin", "HEAD"], false, None)
+            .await
+            .unwrap();
+
+        assert!(!out.status.success());
+        assert_eq!(calls(&dir), 1);
+    }
+
+    #[tokio::test]
+    async fn removes_a_timed_out_clone_before_retrying() {
+        let dir = TempDir::new();
+        // leaves a partial clone behind and hangs on the first call, like a stalled transfer
+        let git_binary = script(
+            dir.path(),
+            "git",
+            &format!(
+                "echo \"$@\" >> {0}/calls\n\
+                 for target; do :; done\n\
+                 if [ -e \"$target\" ]; then echo \"fatal: destination path '$target' already exists\" >&2; exit 128; fi\n\
+                 mkdir \"$target\" && echo \"$(wc -l < {0}/calls)\" > \"$target/attempt\"\n\
+                 if [ \"$(wc -l < {0}/calls)\" -eq 1 ]; then exec sleep 10; fi",
+                dir.path().display()
+            ),
+        );
+        let service = GitService::new(dir.path().join("clone"))
+            .with_git_binary(git_binary)
+            .with_command_timeout(Duration::from_millis(500))
+            .with_retry_policy(RetryPolicy {
+                max_retries: 1,
+                base_delay: Duration::from_millis(1),
+            });
+
+        service
+            .clone_repository("https://example.com/index.git", &CloneOptions::default())
+            .await
+            .unwrap();
+
+        assert_eq!(calls(&dir), 2);
+        let attempt = std::fs::read_to_string(dir.path().join("clone/attempt")).unwrap();
+        assert_eq!(attempt.trim(), "2");
+    }
+
+    #[tokio::test]
+    async fn runs_the_configured_git_binary() {
+        let dir = TempDir::new();
+        let git_binary = script(
+            dir.path(),
+            "echo-git",
+            &format!("echo \"$@\" >> {}/calls", dir.path().display()),
+        );
+        let path = dir.path().join("repo");
+        std::fs::create_dir(&path).unwrap();
+        let service = GitService::new(path).with_git_binary(git_binary);
+
+        let options = CloneOptions {
+            clone_depth: Some(3),
+            filter: CloneFilter::BlobNone,
+            bare: true,
+        };
+        service
+            .clone_repository("https://example.com/index.git", &options)
+            .await
+            .unwrap();
+        service.fetch().await.unwrap();
+        service.diff_stats("c1", "c2").await.unwrap();
+
+        let calls = std::fs::read_to_string(dir.path().join("calls")).unwrap();
+        assert_eq!(
+            calls.lines().collect::<Vec<_>>(),
+            [
+                "clone --filter=blob:none --bare --depth 3 https://example.com/index.git repo",
+                "fetch origin HEAD",
+                "rev-parse --verify --quiet FETCH_HEAD^{commit}",
+                "diff --numstat -z --find-renames c1 c2",
+            ]
+        );
+    }
+
+    fn envs_map(envs: &[(&'static str, OsString)]) -> HashMap<&'static str, OsString> {
+        envs.iter().cloned().collect()
+    }
+
+    #[tokio::test]
+    async fn sets_no_credentials_without_auth() {
+        let service = GitService::new(PathBuf::from("unused"));
+
+        assert!(service.auth_envs().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn hands_the_token_to_git_through_a_private_askpass_script() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let service = GitService::with_auth(
+            PathBuf::from("unused"),
+            AuthConfig::HttpsToken("secret".to_string()),
+        );
+        let envs_of = |envs: Vec<(&'static str, OsString)>| envs_map(&envs);
+        let envs = envs_of(service.auth_envs().await.unwrap());
+
+        assert_eq!(envs["GIT_TERMINAL_PROMPT"], "0");
+        assert_eq!(envs[ASKPASS_TOKEN_ENV], "secret");
+        let askpass = PathBuf::from(&envs["GIT_ASKPASS"]);
+        let dir = askpass.parent().unwrap().to_path_buf();
+        for path in [&askpass, &dir] {
+            let mode = std::fs::metadata(path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o700, "{}", path.display());
+        }
+
+        let answer = |prompt: &str| {
+            let out = std::process::Command::new(&askpass)
+                .arg(prompt)
+                .env(ASKPASS_TOKEN_ENV, "secret")
+                .output()
+                .unwrap();
+            String::from_utf8(out.stdout).unwrap()
+        };
+        assert_eq!(
+            answer("Username for 'https://example.com': "),
+            "x-access-token\n"
+        );
+        assert_eq!(answer("Password for 'https://example.com': "), "secret\n");
+
+        // the script is reused by later commands
+        let again = envs_of(service.auth_envs().await.unwrap());
+        assert_eq!(PathBuf::from(&again["GIT_ASKPASS"]), askpass);
+
+        drop(service);
+        assert!(!dir.exists());
+    }
+
+    #[tokio::test]
+    async fn hands_the_ssh_options_to_git() {
+        let options = SshOptions::default().with_port(2222);
+        let service =
+            GitService::with_auth(PathBuf::from("unused"), AuthConfig::Ssh(options.clone()));
+
+        let envs = envs_map(&service.auth_envs().await.unwrap());
+
+        assert_eq!(envs.len(), 1);
+        assert_eq!(envs["GIT_SSH_COMMAND"], options.command());
+    }
+
+    #[test]
+    fn composes_the_ssh_command() {
+        assert_eq!(SshOptions::default().command(), "ssh");
+
+        let options = SshOptions::default()
+            .with_identity_file(PathBuf::from("/keys/it's a key"))
+            .with_port(2222)
+            .with_known_hosts(PathBuf::from("/etc/known hosts"));
+        let command = options.command();
+        assert_eq!(
+            command,
+            "ssh -o IdentitiesOnly=yes -i '/keys/it'\\''s a key' -p 2222 \
+             -o UserKnownHostsFile='/etc/known hosts'"
+        );
+
+        // git runs it with `sh`, which has to split it back into the original arguments
+        let out = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(format!(
+                "ssh() {{ printf '%s\\n' \"$@\"; }}; {}",
+                command.to_str().unwrap()
+            ))
+            .output()
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(out.stdout)
+                .unwrap()
+                .lines()
+                .collect::<Vec<_>>(),
+            [
+                "-o",
+                "IdentitiesOnly=yes",
+                "-i",
+                "/keys/it's a key",
+                "-p",
+                "2222",
+                "-o",
+                "UserKnownHostsFile=/etc/known hosts",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn passes_the_credentials_to_every_command() {
+        let dir = TempDir::new();
+        let git = script(
+            dir.path(),
+            "git",
+            &format!(
+                "echo \"$GIT_ASKPASS $GIT_TERMINAL_PROMPT ${}\"",
+                ASKPASS_TOKEN_ENV
+            ),
+        );
+        let service = GitService::with_auth(
+            dir.path().to_path_buf(),
+            AuthConfig::HttpsToken("secret".to_string()),
+        )
+        .with_git_binary(git);
+
+        let out = service
+            .call_command(&service.git_binary, &["fetch"], false)
+            .await
+            .unwrap();
+
+        assert!(
+            out.stdout.ends_with("/askpass.sh 0 secret\n"),
+            "{}",
+            out.stdout
+        );
+    }
+
+    /// Every backend fetching `remote` into the repository at `path`.
+    fn backends(path: &Path, remote: &str) -> Vec<Box<dyn GitBackend>> {
+        vec![
+            Box::new(GitService::new(path.to_path_buf()).with_remote(remote.to_string())),
+            #[cfg(feature = "libgit2")]
+            Box::new(
+                crate::git2_backend::Git2Backend::new(path.to_path_buf())
+                    .with_remote(remote.to_string()),
+            ),
+        ]
+    }
+
+    #[tokio::test]
+    async fn fetches_only_the_chosen_remote() {
+        let (origin, mirror) = (Remote::new(), Remote::new());
+        origin.commit(&[("file", "origin")], "initial");
+        mirror.commit(&[("file", "mirror")], "initial");
+        let dir = TempDir::new();
+        let path = dir.path().join("clone");
+        GitService::new(path.clone())
+            .clone_repository(&origin.url(), &CloneOptions::default())
+            .await
+            .unwrap();
+        git(&path, &["remote", "add", "mirror", &mirror.url()]);
+        let origin_head = origin.commit(&[("file", "origin 2")], "second");
+        let mirror_head = mirror.commit(&[("file", "mirror 2")], "second");
+
+        for backend in backends(&path, "origin") {
+            assert_eq!(backend.fetch().await.unwrap(), Some(origin_head.clone()));
+            assert_eq!(
+                backend.fetch_branch("master").await.unwrap(),
+                Some(origin_head.clone())
+            );
+        }
+        let has_object = |hash: &str| {
+            std::process::Command::new("git")
+                .args(["cat-file", "-e", hash])
+                .current_dir(&path)
+                .status()
+                .unwrap()
+                .success()
+        };
+        assert!(!has_object(&mirror_head));
+
+        for backend in backends(&path, "mirror") {
+            assert_eq!(
+                backend.fetch_branch("master").await.unwrap(),
+                Some(mirror_head.clone())
+            );
+        }
+        assert!(has_object(&mirror_head));
+        assert_eq!(
+            git(&path, &["rev-parse", "origin/master"]).trim(),
+            origin_head
+        );
+    }
+
+    #[tokio::test]
+    async fn clones_only_the_last_commits() {
+        let remote = Remote::new();
+        let first = remote.commit(&[("1/a", "a\n")], "first");
+        remote.commit(&[("1/b", "b\n")], "second");
+        let last = remote.commit(&[("1/c", "c\n")], "third");
+        let dir = TempDir::new();
+        let service = GitService::new(dir.path().join("clone"));
+
+        service
+            .clone_repository(
+                &remote.url(),
+                &CloneOptions {
+                    clone_depth: Some(1),
+                    ..CloneOptions::default()
+                },
+            )
+            .await
+            .unwrap();
+
+        let count = git(&dir.path().join("clone"), &["rev-list", "--count", "HEAD"]);
+        assert_eq!(count.trim(), "1");
+
+        // commits before the clone's depth can still be diffed
+        let files = service.diff_commits_name_only(&first, &last).await.unwrap();
+        assert_eq!(files, ["1/b", "1/c"]);
+    }
+
+    #[tokio::test]
+    async fn kills_a_command_that_times_out() {
+        let dir = TempDir::new();
+        let slow = script(
+            dir.path(),
+            "slow",
+            &format!("echo $$ > {}/pid\nexec sleep 30", dir.path().display()),
+        );
+        let service = GitService::new(dir.path().to_path_buf())
+            .with_command_timeout(Duration::from_millis(300));
+
+        let started = std::time::Instant::now();
+        let result = service.call_command(&slow, &[], false).await;
+
+        assert!(
+            matches!(result, Err(GitError::Timeout(timeout)) if timeout == Duration::from_millis(300)),
+            "{:?}",
+            result
+        );
+        assert!(started.elapsed() < Duration::from_secs(10));
+        let pid = std::fs::read_to_string(dir.path().join("pid")).unwrap();
+        assert!(!Path::new("/proc").join(pid.trim()).exists());
+    }
+
+    #[tokio::test]
+    async fn kills_a_cancelled_command() {
+        let dir = TempDir::new();
+        let pid_path = dir.path().join("pid");
+        let slow = script(
+            dir.path(),
+            "slow",
+            &format!(
+                "echo $$ > {}.tmp\nmv {0}.tmp {0}\nexec sleep 30",
+                pid_path.display()
+            ),
+        );
+        let token = CancellationToken::new();
+        let service =
+            GitService::new(dir.path().to_path_buf()).with_cancellation_token(token.clone());
+
+        let cancel = tokio::spawn({
+            let pid_path = pid_path.clone();
+            async move {
+                while !pid_path.exists() {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+                token.cancel();
+            }
+        });
+        let started = std::time::Instant::now();
+        let result = service.call_command(&slow, &[], false).await;
+        cancel.await.unwrap();
+
+        assert!(matches!(result, Err(GitError::Cancelled)), "{:?}", result);
+        assert!(started.elapsed() < Duration::from_secs(10));
+        let pid = std::fs::read_to_string(&pid_path).unwrap();
+        assert!(!Path::new("/proc").join(pid.trim()).exists());
+
+        // once cancelled nothing runs anymore
+        std::fs::remove_file(&pid_path).unwrap();
+        let result = service.call_command(&slow, &[], false).await;
+        assert!(matches!(result, Err(GitError::Cancelled)), "{:?}", result);
+        assert!(!pid_path.exists());
+    }
+
+    #[tokio::test]
+    async fn runs_one_command_at_a_time_with_a_single_permit() {
+        let dir = TempDir::new();
+        // fails if another call is still running
+        let git_binary = script(
+            dir.path(),
+            "git",
+            &format!(
+                "mkdir {0}/running || exit 1\nsleep 0.1\nrmdir {0}/running\necho \"$@\" >> {0}/calls",
+                dir.path().display()
+            ),
+        );
+        let permits = Arc::new(Semaphore::new(1));
+        let service = || {
+            GitService::new(dir.path().to_path_buf())
+                .with_git_binary(git_binary.clone())
+                .with_command_permits(permits.clone())
+        };
+        let (first, second, third) = (service(), service(), service());
+
+        let (a, b, c) = tokio::join!(first.fetch(), second.fetch(), third.fetch());
+
+        a.unwrap();
+        b.unwrap();
+        c.unwrap();
+        // a fetch and a rev-parse each
+        assert_eq!(calls(&dir), 6);
+        assert_eq!(permits.available_permits(), 1);
+
+        // failed and cancelled commands give their permit back as well
+        let failing = script(dir.path(), "failing", "exit 1");
+        let out = first.call_command(&failing, &[], false).await.unwrap();
+        assert!(!out.status.success());
+        assert_eq!(permits.available_permits(), 1);
+
+        let token = CancellationToken::new();
+        let cancelled = service().with_cancellation_token(token.clone());
+        let slow = script(dir.path(), "slow", "exec sleep 30");
+        let (result, ()) = tokio::join!(cancelled.call_command(&slow, &[], false), async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            token.cancel();
+        });
+        assert!(matches!(result, Err(GitError::Cancelled)), "{:?}", result);
+        assert_eq!(permits.available_permits(), 1);
+    }
+
+    #[tokio::test]
+    async fn captures_both_streams() {
+        let dir = TempDir::new();
+        let program = script(dir.path(), "both", "echo out\necho err >&2\necho more out");
+        let service = GitService::new(dir.path().to_path_buf());
+
+        let out = service.call_command(&program, &[], false).await.unwrap();
+
+        assert!(out.status.success());
+        assert_eq!(out.stdout, "out\nmore out\n");
+        assert_eq!(out.stderr, "err\n");
+    }
+
+    #[tokio::test]
+    async fn runs_in_the_parent_of_the_repository() {
+        let dir = TempDir::new();
+        let program = script(dir.path(), "pwd", "pwd");
+        let repository_path = dir.path().join("clone");
+        std::fs::create_dir(&repository_path).unwrap();
+        let service = GitService::new(repository_path.clone());
+
+        let out = service.call_command(&program, &[], false).await.unwrap();
+        assert_eq!(Path::new(out.stdout.trim()), repository_path);
+
+        let out = service.call_command(&program, &[], true).await.unwrap();
+        assert_eq!(Path::new(out.stdout.trim()), dir.path());
+    }
+
+    #[tokio::test]
+    async fn diffs_lines_of_plain_text_files() {
+        let remote = Remote::new();
+        let first = remote.commit(
+            &[
+                ("notes.txt", "one\ntwo\nthree\n"),
+                ("docs/other.md", "other\n"),
+            ],
+            "first",
+        );
+        let second = remote.commit(
+            &[("notes.txt", "one\n2\nthree\n"), ("docs/new.md", "new\n")],
+            "second",
+        );
+        let dir = TempDir::new();
+        let service = GitService::new(dir.path().join("clone"));
+        service
+            .clone_repository(&remote.url(), &CloneOptions::default())
+            .await
+            .unwrap();
+
+        let change = |path: &str, kind, line: &str| LineChange {
+            path: path.to_string(),
+            kind,
+            line: line.to_string(),
+        };
+        let expected = vec![
+            change("docs/new.md", ChangeKind::Added, "new"),
+            change("notes.txt", ChangeKind::Removed, "two"),
+            change("notes.txt", ChangeKind::Added, "2"),
+        ];
+        assert_eq!(
+            service.diff_commits_raw(&first, &second).await.unwrap(),
+            expected
+        );
+
+        #[cfg(feature = "libgit2")]
+        assert_eq!(
+            crate::git2_backend::Git2Backend::new(dir.path().join("clone"))
+                .diff_commits_raw(&first, &second)
+                .await
+                .unwrap(),
+            expected
+        );
+    }
+
+    #[tokio::test]
+    async fn diffs_files_without_a_trailing_newline() {
+        let remote = Remote::new();
+        let first = remote.commit(&[("a.txt", "one\ntwo"), ("b.txt", "b")], "first");
+        let second = remote.commit(&[("a.txt", "one\n2"), ("b.txt", "b\n")], "second");
+        let dir = TempDir::new();
+        let service = GitService::new(dir.path().join("clone"));
+        service
+            .clone_repository(&remote.url(), &CloneOptions::default())
+            .await
+            .unwrap();
+
+        let change = |path: &str, kind, line: &str| LineChange {
+            path: path.to_string(),
+            kind,
+            line: line.to_string(),
+        };
+        let expected = vec![
+            change("a.txt", ChangeKind::Removed, "two"),
+            change("a.txt", ChangeKind::Added, "2"),
+            change("b.txt", ChangeKind::Removed, "b"),
+            change("b.txt", ChangeKind::Added, "b"),
+        ];
+        assert_eq!(
+            service.diff_commits_raw(&first, &second).await.unwrap(),
+            expected
+        );
+
+        #[cfg(feature = "libgit2")]
+        assert_eq!(
+            crate::git2_backend::Git2Backend::new(dir.path().join("clone"))
+                .diff_commits_raw(&first, &second)
+                .await
+                .unwrap(),
+            expected
+        );
+    }
+
+    #[tokio::test]
+    async fn reads_the_metadata_of_a_commit() {
+        let remote = Remote::new();
+        remote.commit(&[("file", "first")], "first");
+        let head = remote.commit(
+            &[("file", "second")],
+            "Add the second version\n\nWith a body",
+        );
+        let dir = TempDir::new();
+        let path = dir.path().join("clone");
+        GitService::new(path.clone())
+            .clone_repository(&remote.url(), &CloneOptions::default())
+            .await
+            .unwrap();
+
+        for backend in backends(&path, DEFAULT_REMOTE) {
+            let meta = backend.get_commit_metadata("HEAD").await.unwrap();
+
+            assert_eq!(meta.hash, head);
+            assert_eq!(meta.author_name, "Test Author");
+            assert_eq!(meta.author_email, "author@example.com");
+            assert_eq!(meta.subject, "Add the second version");
+            let age = Utc::now() - meta.committed_at.unwrap();
+            assert!(age.num_seconds().abs() < 600, "{:?}", meta.committed_at);
+        }
+    }
+
+    #[test]
+    fn parses_commit_metadata_with_missing_fields() {
+        let meta = CommitMeta::parse("abc\0Name\0\0not a timestamp");
+        assert_eq!(
+            meta,
+            CommitMeta {
+                hash: "abc".to_string(),
+                author_name: "Name".to_string(),
+                author_email: String::new(),
+                committed_at: None,
+                subject: String::new(),
+            }
+        );
+
+        let meta = CommitMeta::parse(
+            &["abc", "Name", "name@example.com", "1700000000", "Subject"].join("\0"),
+        );
+        assert_eq!(
+            meta.committed_at,
+            DateTime::from_timestamp(1_700_000_000, 0)
+        );
+        assert_eq!(meta.subject, "Subject");
+
+        assert_eq!(CommitMeta::parse("").hash, "");
+    }
+
+    #[test]
+    fn parses_ls_remote_output() {
+        let output = "a1\tHEAD\n\
+                      a1\trefs/heads/master\n\
+                      b2\trefs/tags/v1.0.0\n\
+                      c3\trefs/tags/v2.0.0\n\
+                      a1\trefs/tags/v2.0.0^{}\n\
+                      d4\trefs/pull/1/head\n\
+                      malformed line\n\
+                      e5\trefs/tags/gone^{}\n";
+
+        let refs = RemoteRef::parse_ls_remote(output);
+
+        let remote_ref = |name: &str, kind, hash: &str, peeled: Option<&str>| RemoteRef {
+            name: name.to_string(),
+            kind,
+            hash: hash.to_string(),
+            peeled: peeled.map(str::to_string),
+        };
+        assert_eq!(
+            refs,
+            [
+                remote_ref("HEAD", RefKind::Head, "a1", None),
+                remote_ref("master", RefKind::Branch, "a1", None),
+                remote_ref("v1.0.0", RefKind::Tag, "b2", None),
+                remote_ref("v2.0.0", RefKind::Tag, "c3", Some("a1")),
+                remote_ref("refs/pull/1/head", RefKind::Other, "d4", None),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn lists_the_refs_of_the_remote() {
+        let remote = Remote::new();
+        let first = remote.commit(&[("file", "first")], "first");
+        git(remote.path(), &["tag", "v1.0.0"]);
+        git(remote.path(), &["branch", "stable"]);
+        let head = remote.commit(&[("file", "second")], "second");
+        git(remote.path(), &["tag", "-a", "-m", "release", "v2.0.0"]);
+        let tag = git(remote.path(), &["rev-parse", "v2.0.0"])
+            .trim()
+            .to_string();
+        let dir = TempDir::new();
+        let service = GitService::new(dir.path().join("clone"));
+        service
+            .clone_repository(&remote.url(), &CloneOptions::default())
+            .await
+            .unwrap();
+
+        let refs = service.list_remote_refs().await.unwrap();
+
+        let summary = refs
+            .iter()
+            .map(|r| {
+                (
+                    r.kind,
+                    r.name.as_str(),
+                    r.hash.as_str(),
+                    r.peeled.as_deref(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            [
+                (RefKind::Head, "HEAD", head.as_str(), None),
+                (RefKind::Branch, "master", head.as_str(), None),
+                (RefKind::Branch, "stable", first.as_str(), None),
+                (RefKind::Tag, "v1.0.0", first.as_str(), None),
+                (RefKind::Tag, "v2.0.0", tag.as_str(), Some(head.as_str())),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn only_diffs_files_matching_the_path_filters() {
+        let remote = Remote::new();
+        let first = remote.commit(&[("config.json", "{}")], "initial");
+        let (serde, tokio) = (index_path("serde"), index_path("tokio"));
+        let second = remote.commit(
+            &[
+                (&serde, &index_line("serde", "1.0.0", false)),
+                (&tokio, &index_line("tokio", "1.0.0", false)),
+                ("config.json", r#"{"dl":"https://example.com"}"#),
+            ],
+            "serde and tokio",
+        );
+        let dir = TempDir::new();
+        let path = dir.path().join("clone");
+        let filters = vec!["se/".to_string()];
+        let service = GitService::new(path.clone()).with_path_filters(filters.clone());
+        service
+            .clone_repository(&remote.url(), &CloneOptions::default())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            service
+                .diff_commits_name_only(&first, &second)
+                .await
+                .unwrap(),
+            [serde.as_str()]
+        );
+        let serde_only = HashSet::from([DiffAction::Add(CrateChange::new(
+            "serde",
+            Some("1.0.0".to_string()),
+        ))]);
+        assert_eq!(
+            service.diff_commits(&first, &second).await.unwrap(),
+            serde_only
+        );
+        #[cfg(feature = "libgit2")]
+        assert_eq!(
+            crate::git2_backend::Git2Backend::new(path.clone())
+                .with_path_filters(filters)
+                .diff_commits(&first, &second)
+                .await
+                .unwrap(),
+            serde_only
+        );
+
+        // without filters every file is diffed
+        assert_eq!(
+            GitService::new(path)
+                .diff_commits_name_only(&first, &second)
+                .await
+                .unwrap(),
+            ["config.json", serde.as_str(), tokio.as_str()]
+        );
+    }
+
+    #[tokio::test]
+    async fn renames_add_and_remove_nothing() {
+        let remote = Remote::new();
+        let lines = [
+            index_line("serde", "1.0.0", false),
+            index_line("serde", "1.0.1", false),
+        ]
+        .join("\n")
+            + "\n";
+        let first = remote.commit(&[("se/rd/serde", &lines)], "initial");
+        std::fs::create_dir(remote.path().join("moved")).unwrap();
+        git(remote.path(), &["mv", "se/rd/serde", "moved/serde"]);
+        let renamed = remote.commit(&[], "move serde");
+        // a rename with one changed line only reports that line
+        let updated = remote.commit(
+            &[("moved/serde", &lines.replace(r#""1.0.1","#, r#""1.0.2","#))],
+            "serde 1.0.2",
+        );
+        git(remote.path(), &["mv", "moved/serde", "se/rd/serde"]);
+        let moved_back = remote.commit(&[], "move serde back");
+        let dir = TempDir::new();
+        let path = dir.path().join("clone");
+        GitService::new(path.clone())
+            .clone_repository(&remote.url(), &CloneOptions::default())
+            .await
+            .unwrap();
+
+        for backend in backends(&path, DEFAULT_REMOTE) {
+            assert!(
+                backend
+                    .diff_commits(&first, &renamed)
+                    .await
+                    .unwrap()
+                    .is_empty()
+            );
+            assert_eq!(
+                backend.diff_commits(&first, &moved_back).await.unwrap(),
+                HashSet::from([DiffAction::Update(CrateChange::new(
+                    "serde",
+                    Some("1.0.2".to_string())
+                ))])
+            );
+            assert_eq!(
+                backend
+                    .diff_commits(&renamed, &updated)
+                    .await
+                    .unwrap()
+                    .len(),
+                1
+            );
+        }
+    }
+
+    #[test]
+    fn parses_every_file_of_a_diff_on_its_own() {
+        let diff = "diff --git a.txt a.txt\n\
+                    --- a.txt\n\
+                    +++ a.txt\n\
+                    @@ -1 +1 @@\n\
+                    -old a\n\
+                    +new a\n\
+                    diff --git old.txt new.txt\n\
+                    similarity index 100%\n\
+                    rename from old.txt\n\
+                    rename to new.txt\n\
+                    diff --git script.sh script.sh\n\
+                    old mode 100644\n\
+                    new mode 100755\n\
+                    diff --git z.txt z.txt\n\
+                    --- z.txt\n\
+                    +++ z.txt\n\
+                    @@ -1 +1,2 @@\n \
+                    z\n\
+                    +added z\n";
+
+        let patches = parse_patches(diff).unwrap();
+
+        let files = patches
+            .iter()
+            .map(|patch| (patch.old.path.as_ref(), patch.new.path.as_ref()))
+            .collect::<Vec<_>>();
+        // the pure rename is a file without hunks, the mode change is skipped
+        assert_eq!(
+            files,
+            [
+                ("a.txt", "a.txt"),
+                ("old.txt", "new.txt"),
+                ("z.txt", "z.txt")
+            ]
+        );
+        assert!(is_rename(&patches[1]) && patches[1].hunks.is_empty());
+        assert_eq!(patches[2].hunks[0].lines.len(), 2);
+        assert!(parse_patches("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn parses_numstat_output() {
+        let output = concat!(
+            "3\t1\ta.txt\0",
+            "-\t-\timage.png\0",
+            "0\t0\t\0old name\0new\tname\0"
+        );
+
+        let stat = |path: &str, added, deleted| FileStat {
+            path: path.to_string(),
+            added,
+            deleted,
+        };
+        assert_eq!(
+            FileStat::parse_numstat(output).unwrap(),
+            [
+                stat("a.txt", Some(3), Some(1)),
+                stat("image.png", None, None),
+                stat("new\tname", Some(0), Some(0)),
+            ]
+        );
+        assert!(FileStat::parse_numstat("").unwrap().is_empty());
+
+        for invalid in ["3\t1\0", "x\t1\ta.txt\0", "1\t1\t\0only old\0"] {
+            let result = FileStat::parse_numstat(invalid);
+            assert!(
+                matches!(result, Err(GitError::DiffContentError(_))),
+                "{:?}: {:?}",
+                invalid,
+                result
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn counts_the_changed_lines_of_every_file() {
+        let remote = Remote::new();
+        let first = remote.commit(
+            &[("text.txt", "a\nb\nc\n"), ("image.png", "\0\u{1}")],
+            "initial",
+        );
+        let second = remote.commit(
+            &[
+                ("text.txt", "a\nB\nc\nd\n"),
+                ("image.png", "\0\u{2}"),
+                ("with\ttab.txt", "new\n"),
+            ],
+            "second",
+        );
+        let dir = TempDir::new();
+        let path = dir.path().join("clone");
+        GitService::new(path.clone())
+            .clone_repository(&remote.url(), &CloneOptions::default())
+            .await
+            .unwrap();
+
+        for backend in backends(&path, DEFAULT_REMOTE) {
+            let mut stats = backend.diff_stats(&first, &second).await.unwrap();
+            stats.sort_by(|a, b| a.path.cmp(&b.path));
+
+            let counts = stats
+                .iter()
+                .map(|stat| (stat.path.as_str(), stat.added, stat.deleted))
+                .collect::<Vec<_>>();
+            assert_eq!(
+                counts,
+                [
+                    ("image.png", None, None),
+                    ("text.txt", Some(2), Some(1)),
+                    ("with\ttab.txt", Some(1), Some(0)),
+                ]
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn reports_a_corrupted_repository_as_invalid() {
+        let remote = Remote::new();
+        remote.commit(&[("a.txt", "a\n")], "initial");
+        remote.commit(&[("b.txt", "b\n")], "second");
+        let dir = TempDir::new();
+        let path = dir.path().join("clone");
+        let options = CloneOptions {
+            filter: CloneFilter::None,
+            ..CloneOptions::default()
+        };
+        GitService::new(path.clone())
+            .clone_repository(&remote.url(), &options)
+            .await
+            .unwrap();
+
+        for backend in backends(&path, DEFAULT_REMOTE) {
+            assert!(backend.verify().await.unwrap());
+        }
+
+        // the refs are left pointing to objects that are gone
+        for pack in std::fs::read_dir(path.join("objects/pack")).unwrap() {
+            std::fs::remove_file(pack.unwrap().path()).unwrap();
+        }
+        for backend in backends(&path, DEFAULT_REMOTE) {
+            assert!(!backend.verify().await.unwrap());
+        }
+
+        std::fs::remove_dir_all(&path).unwrap();
+        for backend in backends(&path, DEFAULT_REMOTE) {
+            assert!(!backend.verify().await.unwrap());
+        }
+    }
+
+    fn assert_failed<T: std::fmt::Debug>(
+        result: Result<T, GitError>,
+        expected_command: &str,
+        expected_stderr: &str,
+    ) {
+        match result {
+            Err(GitError::Failed {
+                command, stderr, ..
+            }) => {
+                assert_eq!(command, expected_command);
+                assert!(stderr.contains(expected_stderr), "{}", stderr);
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn returns_the_stderr_of_a_failed_command() {
+        let dir = TempDir::new();
+        let service = GitService::new(dir.path().join("clone"));
+
+        let missing = format!("file://{}", dir.path().join("missing").display());
+        let result = service
+            .clone_repository(&missing, &CloneOptions::default())
+            .await;
+        assert_failed(result, "clone", "does not appear to be a git repository");
+
+        let remote = Remote::new();
+        let head = remote.commit(&[("file", "content")], "initial");
+        service
+            .clone_repository(&remote.url(), &CloneOptions::default())
+            .await
+            .unwrap();
+
+        let result = service.diff_stats(&head, "0000000").await;
+        assert_failed(result, "diff", "0000000");
+
+        drop(remote);
+        assert_failed(
+            service.fetch().await,
+            "fetch",
+            "does not appear to be a git repository",
+        );
+    }
+
+    #[tokio::test]
+    async fn finds_no_commit_in_an_empty_remote() {
+        let remote = Remote::new();
+        let dir = TempDir::new();
+        let path = dir.path().join("clone");
+        GitService::new(path.clone())
+            .clone_repository(&remote.url(), &CloneOptions::default())
+            .await
+            .unwrap();
+
+        for backend in backends(&path, DEFAULT_REMOTE) {
+            assert_eq!(backend.fetch().await.unwrap(), None);
+            assert_eq!(
+                backend
+                    .get_current_commit_hash_from_fetch_head()
+                    .await
+                    .unwrap(),
+                None
+            );
+            assert_eq!(backend.fetch_branch("master").await.unwrap(), None);
+        }
+
+        let head = remote.commit(&[("file", "content")], "initial");
+        for backend in backends(&path, DEFAULT_REMOTE) {
+            assert_eq!(backend.fetch().await.unwrap(), Some(head.clone()));
+            assert_eq!(
+                backend.fetch_branch("master").await.unwrap(),
+                Some(head.clone())
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn fails_on_invalid_json_only_when_asked_to() {
+        let remote = Remote::new();
+        let first = remote.commit(&[("config.json", "{}")], "initial");
+        let line = index_line("serde", "1.0.0", false);
+        let second = remote.commit(
+            &[(
+                &index_path("serde"),
+                &format!("{}\n{{\"name\": oops}}\n", line),
+            )],
+            "broken line",
+        );
+        let dir = TempDir::new();
+        let path = dir.path().join("clone");
+        GitService::new(path.clone())
+            .clone_repository(&remote.url(), &CloneOptions::default())
+            .await
+            .unwrap();
+
+        let backends: Vec<(Box<dyn GitBackend>, Box<dyn GitBackend>)> = vec![
+            (
+                Box::new(GitService::new(path.clone()).with_diff_error_mode(DiffErrorMode::Fail)),
+                Box::new(GitService::new(path.clone())),
+            ),
+            #[cfg(feature = "libgit2")]
+            (
+                Box::new(
+                    crate::git2_backend::Git2Backend::new(path.clone())
+                        .with_diff_error_mode(DiffErrorMode::Fail),
+                ),
+                Box::new(crate::git2_backend::Git2Backend::new(path.clone())),
+            ),
+        ];
+
+        for (failing, skipping) in backends {
+            let result = failing.diff_commits(&first, &second).await;
+            assert!(
+                matches!(result, Err(GitError::JsonError(_))),
+                "{:?}",
+                result
+            );
+
+            assert_eq!(
+                skipping.diff_commits(&first, &second).await.unwrap(),
+                HashSet::from([DiffAction::Add(CrateChange::new(
+                    "serde",
+                    Some("1.0.0".to_string())
+                ))])
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn passes_the_diff_algorithm_to_git_diff() {
+        let dir = TempDir::new();
+        let git_binary = script(
+            dir.path(),
+            "echo-git",
+            &format!("echo \"$@\" >> {}/calls", dir.path().display()),
+        );
+        let path = dir.path().join("repo");
+        std::fs::create_dir(&path).unwrap();
+        let service = GitService::new(path)
+            .with_git_binary(git_binary)
+            .with_diff_algorithm(DiffAlgorithm::Histogram)
+            .with_path_filters(vec!["se/".to_string()]);
+
+        service.diff_commits_name_only("c1", "c2").await.unwrap();
+        service.diff_stats("c1", "c2").await.unwrap();
+
+        let calls = std::fs::read_to_string(dir.path().join("calls")).unwrap();
+        assert_eq!(
+            calls.lines().collect::<Vec<_>>(),
+            [
+                "diff --diff-algorithm=histogram --name-only --find-renames c1 c2 -- se/",
+                "diff --diff-algorithm=histogram --numstat -z --find-renames c1 c2 -- se/",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn diffs_differently_with_another_algorithm() {
+        let remote = Remote::new();
+        let first = remote.commit(&[("list.txt", "a\nb\nc\nc\na\na\n}\n}\n")], "first");
+        let second = remote.commit(&[("list.txt", "c\na\nx\nb\n")], "second");
+        let dir = TempDir::new();
+        let path = dir.path().join("clone");
+        GitService::new(path.clone())
+            .clone_repository(&remote.url(), &CloneOptions::default())
+            .await
+            .unwrap();
+
+        let added = |changes: Vec<LineChange>| {
+            changes
+                .into_iter()
+                .filter(|change| change.kind == ChangeKind::Added)
+                .map(|change| change.line)
+                .collect::<Vec<_>>()
+        };
+        let cli = |algorithm| GitService::new(path.clone()).with_diff_algorithm(algorithm);
+
+        let myers = added(
+            cli(DiffAlgorithm::Myers)
+                .diff_commits_raw(&first, &second)
+                .await
+                .unwrap(),
+        );
+        let patience = added(
+            cli(DiffAlgorithm::Patience)
+                .diff_commits_raw(&first, &second)
+                .await
+                .unwrap(),
+        );
+        assert_eq!(myers, ["x", "b"]);
+        assert_eq!(patience, ["c", "x"]);
+        // git's default
+        assert_eq!(
+            added(
+                GitService::new(path.clone())
+                    .diff_commits_raw(&first, &second)
+                    .await
+                    .unwrap()
+            ),
+            myers
+        );
+
+        #[cfg(feature = "libgit2")]
+        {
+            let libgit2 = |algorithm| {
+                crate::git2_backend::Git2Backend::new(path.clone()).with_diff_algorithm(algorithm)
+            };
+            for (algorithm, expected) in [
+                (DiffAlgorithm::Myers, &myers),
+                (DiffAlgorithm::Patience, &patience),
+            ] {
+                let changes = libgit2(algorithm)
+                    .diff_commits_raw(&first, &second)
+                    .await
+                    .unwrap();
+                assert_eq!(&added(changes), expected, "{:?}", algorithm);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn diffs_bare_and_working_tree_clones_alike() {
+        let remote = Remote::new();
+        let first = remote.commit(
+            &[(&index_path("serde"), &index_line("serde", "1.0.0", false))],
+            "serde 1.0.0",
+        );
+        let dir = TempDir::new();
+        for bare in [true, false] {
+            let options = CloneOptions {
+                bare,
+                ..CloneOptions::default()
+            };
+            GitService::new(dir.path().join(if bare { "bare" } else { "tree" }))
+                .clone_repository(&remote.url(), &options)
+                .await
+                .unwrap();
+        }
+        assert!(dir.path().join("bare/HEAD").is_file());
+        assert!(dir.path().join("tree/.git").is_dir());
+        assert!(dir.path().join("tree").join(index_path("serde")).is_file());
+
+        let second = remote.commit(
+            &[
+                (&index_path("serde"), &index_line("serde", "1.0.1", false)),
+                (&index_path("tokio"), &index_line("tokio", "1.48.0", false)),
+            ],
+            "serde 1.0.1, tokio 1.48.0",
+        );
+        let expected = HashSet::from([
+            DiffAction::Update(CrateChange::new("serde", Some("1.0.1".to_string()))),
+            DiffAction::Add(CrateChange::new("tokio", Some("1.48.0".to_string()))),
+        ]);
+
+        for path in ["bare", "tree"].map(|name| dir.path().join(name)) {
+            for backend in backends(&path, DEFAULT_REMOTE) {
+                assert_eq!(backend.fetch().await.unwrap().as_ref(), Some(&second));
+                assert_eq!(
+                    backend
+                        .get_current_commit_hash_from_fetch_head()
+                        .await
+                        .unwrap(),
+                    Some(second.clone())
+                );
+                assert_eq!(
+                    backend.diff_commits(&first, &second).await.unwrap(),
+                    expected,
+                    "{}",
+                    path.display()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn builds_the_clone_filter_argument() {
+        assert_eq!(CloneFilter::None.arg(), None);
+        assert_eq!(
+            CloneFilter::BlobNone.arg().as_deref(),
+            Some("--filter=blob:none")
+        );
+        assert_eq!(
+            CloneFilter::BlobLimit(1024).arg().as_deref(),
+            Some("--filter=blob:limit=1024")
+        );
+        assert_eq!(
+            CloneFilter::TreeZero.arg().as_deref(),
+            Some("--filter=tree:0")
+        );
+        assert_eq!(CloneFilter::default(), CloneFilter::BlobNone);
+    }
+
+    #[tokio::test]
+    async fn diffs_a_treeless_clone() {
+        let remote = Remote::new();
+        git(remote.path(), &["config", "uploadpack.allowFilter", "true"]);
+        let first = remote.commit(
+            &[(&index_path("serde"), &index_line("serde", "1.0.0", false))],
+            "serde 1.0.0",
+        );
+        let second = remote.commit(
+            &[(&index_path("serde"), &index_line("serde", "1.0.1", false))],
+            "serde 1.0.1",
+        );
+        let dir = TempDir::new();
+        let path = dir.path().join("clone");
+        let service = GitService::new(path.clone());
+        let options = CloneOptions {
+            filter: CloneFilter::TreeZero,
+            ..CloneOptions::default()
+        };
+        service
+            .clone_repository(&remote.url(), &options)
+            .await
+            .unwrap();
+        assert_eq!(
+            git(&path, &["config", "remote.origin.partialclonefilter"]).trim(),
+            "tree:0"
+        );
+
+        // the trees of both commits are fetched once the diff needs them
+        assert_eq!(
+            service.diff_commits(&first, &second).await.unwrap(),
+            HashSet::from([DiffAction::Update(CrateChange::new(
+                "serde",
+                Some("1.0.1".to_string())
+            ))])
+        );
+    }
 }
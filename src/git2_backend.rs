@@ -0,0 +1,708 @@
+use std::{
+    collections::HashSet,
+    ffi::OsStr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use chrono::DateTime;
+use git2::{
+    Cred, CredentialType, Diff, DiffFindOptions, DiffFormat, DiffOptions, FetchOptions,
+    RemoteCallbacks, Repository, RepositoryOpenFlags, build::RepoBuilder,
+};
+use tokio::{sync::Semaphore, task::JoinHandle};
+use tokio_util::sync::CancellationToken;
+use tracing::{instrument, log};
+
+use crate::crates_index::{self, DiffAction, DiffErrorMode};
+use crate::git::{
+    AuthConfig, ChangeKind, CloneOptions, CommitMeta, DEFAULT_COMMAND_TIMEOUT, DEFAULT_REMOTE,
+    DiffAlgorithm, FileStat, GitBackend, GitError, LineChange, SshOptions,
+};
+
+/// [`GitBackend`] running clone, fetch and diff in-process through libgit2.
+///
+/// Produces the same [`DiffAction`]s as [`crate::git::GitService`], but neither
+/// retries failed fetches nor deepens shallow clones. Every operation runs on the
+/// blocking thread pool.
+#[derive(Debug, Clone)]
+pub struct Git2Backend {
+    repository_path: PathBuf,
+    auth: AuthConfig,
+    /// Time a single clone or fetch may take.
+    command_timeout: Duration,
+    diff_error_mode: DiffErrorMode,
+    path_filters: Vec<String>,
+    diff_algorithm: Option<DiffAlgorithm>,
+    remote: String,
+    cancellation_token: CancellationToken,
+    /// Shared with [`crate::git::GitService`] to bound the git operations running at once.
+    command_permits: Option<Arc<Semaphore>>,
+}
+
+impl Git2Backend {
+    pub fn new(repository_path: PathBuf) -> Self {
+        Self::with_auth(repository_path, AuthConfig::None)
+    }
+
+    pub fn with_auth(repository_path: PathBuf, auth: AuthConfig) -> Self {
+        Self {
+            repository_path,
+            auth,
+            command_timeout: DEFAULT_COMMAND_TIMEOUT,
+            diff_error_mode: DiffErrorMode::default(),
+            path_filters: Vec::new(),
+            diff_algorithm: None,
+            remote: DEFAULT_REMOTE.to_string(),
+            cancellation_token: CancellationToken::new(),
+            command_permits: None,
+        }
+    }
+
+    pub fn with_command_timeout(mut self, command_timeout: Duration) -> Self {
+        self.command_timeout = command_timeout;
+        self
+    }
+
+    pub fn with_diff_error_mode(mut self, diff_error_mode: DiffErrorMode) -> Self {
+        self.diff_error_mode = diff_error_mode;
+        self
+    }
+
+    /// Restricts diffs to files matching one of the pathspecs, all files if empty.
+    pub fn with_path_filters(mut self, path_filters: Vec<String>) -> Self {
+        self.path_filters = path_filters;
+        self
+    }
+
+    /// libgit2 has no histogram algorithm, [`DiffAlgorithm::Histogram`] uses patience, which
+    /// it extends.
+    pub fn with_diff_algorithm(mut self, diff_algorithm: DiffAlgorithm) -> Self {
+        self.diff_algorithm = Some(diff_algorithm);
+        self
+    }
+
+    /// Fetches `remote` instead of `origin`.
+    pub fn with_remote(mut self, remote: String) -> Self {
+        self.remote = remote;
+        self
+    }
+
+    /// Aborts running transfers and fails new operations with [`GitError::Cancelled`] once
+    /// `cancellation_token` is cancelled.
+    pub fn with_cancellation_token(mut self, cancellation_token: CancellationToken) -> Self {
+        self.cancellation_token = cancellation_token;
+        self
+    }
+
+    /// Only runs an operation while holding a permit of `command_permits`.
+    pub fn with_command_permits(mut self, command_permits: Arc<Semaphore>) -> Self {
+        self.command_permits = Some(command_permits);
+        self
+    }
+
+    /// Runs `f` on the blocking thread pool while holding a command permit.
+    async fn run<T, F>(&self, f: F) -> Result<T, GitError>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> Result<T, GitError> + Send + 'static,
+    {
+        self.spawn(f).await?.await.map_err(std::io::Error::from)?
+    }
+
+    /// Like [`Self::run`], but stops waiting for the transfer `f` once it took longer than
+    /// the command timeout or the cancellation token is cancelled.
+    ///
+    /// libgit2 only checks both while data arrives, a connection that hangs would block
+    /// forever. The abandoned thread keeps its permit until libgit2 gives up on it.
+    async fn run_transfer<T, F>(&self, f: F) -> Result<T, GitError>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> Result<T, GitError> + Send + 'static,
+    {
+        let task = self.spawn(f).await?;
+
+        tokio::select! {
+            result = tokio::time::timeout(self.command_timeout, task) => match result {
+                Ok(result) => result.map_err(std::io::Error::from)?,
+                Err(_) => Err(GitError::Timeout(self.command_timeout)),
+            },
+            _ = self.cancellation_token.cancelled() => Err(GitError::Cancelled),
+        }
+    }
+
+    /// Spawns `f` on the blocking thread pool once a command permit is available, which is
+    /// released when `f` returns.
+    async fn spawn<T, F>(&self, f: F) -> Result<JoinHandle<Result<T, GitError>>, GitError>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> Result<T, GitError> + Send + 'static,
+    {
+        if self.cancellation_token.is_cancelled() {
+            return Err(GitError::Cancelled);
+        }
+
+        let permit = match &self.command_permits {
+            Some(permits) => tokio::select! {
+                permit = permits.clone().acquire_owned() => Some(
+                    permit.map_err(|_| std::io::Error::other("git command semaphore was closed"))?,
+                ),
+                _ = self.cancellation_token.cancelled() => return Err(GitError::Cancelled),
+            },
+            None => None,
+        };
+
+        Ok(tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            f()
+        }))
+    }
+
+    /// Opens the repository and runs `f` on it, see [`Self::run`].
+    async fn with_repository<T, F>(&self, f: F) -> Result<T, GitError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Repository) -> Result<T, GitError> + Send + 'static,
+    {
+        let path = self.repository_path.clone();
+
+        self.run(move || f(&open_repository(path)?)).await
+    }
+
+    /// What [`fetch_options`] needs, the options themselves can't be sent to another thread.
+    fn transfer_settings(&self) -> (AuthConfig, CancellationToken, Duration) {
+        (
+            self.auth.clone(),
+            self.cancellation_token.clone(),
+            self.command_timeout,
+        )
+    }
+
+    /// Error of a failed transfer, libgit2 only reports that [`fetch_options`] aborted it.
+    fn transfer_error(&self, err: git2::Error) -> GitError {
+        if self.cancellation_token.is_cancelled() {
+            GitError::Cancelled
+        } else if err.code() == git2::ErrorCode::User {
+            GitError::Timeout(self.command_timeout)
+        } else {
+            err.into()
+        }
+    }
+
+    /// Clones `git_url` into the repository path, without a working tree if
+    /// [`CloneOptions::bare`] is set.
+    ///
+    /// libgit2 doesn't support partial clones, so unlike the CLI every blob is fetched.
+    pub async fn clone_repository(
+        &self,
+        git_url: &str,
+        options: &CloneOptions,
+    ) -> Result<(), GitError> {
+        let (auth, token, timeout) = self.transfer_settings();
+        let (depth, bare) = (options.clone_depth, options.bare);
+        let (git_url, path) = (git_url.to_string(), self.repository_path.clone());
+
+        let result = self
+            .run_transfer(move || {
+                let mut fetch_options = fetch_options(auth, token, timeout);
+                if let Some(depth) = depth {
+                    fetch_options.depth(depth.try_into().unwrap_or(i32::MAX));
+                }
+
+                RepoBuilder::new()
+                    .bare(bare)
+                    .fetch_options(fetch_options)
+                    .clone(&git_url, &path)?;
+
+                Ok(())
+            })
+            .await;
+
+        match result {
+            Err(GitError::Libgit2(err)) => Err(self.transfer_error(err)),
+            result => result,
+        }
+    }
+
+    /// Fetches `refspec` of the remote, `FETCH_HEAD` is written for every fetched ref.
+    async fn fetch_refspec(&self, refspec: String) -> Result<(), GitError> {
+        let (auth, token, timeout) = self.transfer_settings();
+        let (remote, path) = (self.remote.clone(), self.repository_path.clone());
+
+        let result = self
+            .run_transfer(move || {
+                let repository = open_repository(path)?;
+                let mut fetch_options = fetch_options(auth, token, timeout);
+                repository.find_remote(&remote)?.fetch(
+                    &[&refspec],
+                    Some(&mut fetch_options),
+                    None,
+                )?;
+
+                Ok(())
+            })
+            .await;
+
+        match result {
+            Err(GitError::Libgit2(err)) => Err(self.transfer_error(err)),
+            result => result,
+        }
+    }
+
+    /// Every added or removed line between two commits together with its file.
+    pub async fn diff_commits_raw(&self, c1: &str, c2: &str) -> Result<Vec<LineChange>, GitError> {
+        let (c1, c2) = (c1.to_string(), c2.to_string());
+        let path_filters = self.path_filters.clone();
+        let diff_algorithm = self.diff_algorithm;
+
+        self.with_repository(move |repository| {
+            let diff = diff_trees(repository, &c1, &c2, &path_filters, diff_algorithm)?;
+
+            let mut changes = Vec::new();
+            diff.print(DiffFormat::Patch, |delta, _hunk, line| {
+                let (kind, file) = match line.origin() {
+                    '+' => (ChangeKind::Added, delta.new_file()),
+                    '-' => (ChangeKind::Removed, delta.old_file()),
+                    _ => return true,
+                };
+                let content = String::from_utf8_lossy(line.content());
+
+                changes.push(LineChange {
+                    path: file
+                        .path()
+                        .map(|path| path.to_string_lossy().into_owned())
+                        .unwrap_or_default(),
+                    kind,
+                    line: content.trim_end_matches(['\r', '\n']).to_string(),
+                });
+
+                true
+            })?;
+
+            Ok(changes)
+        })
+        .await
+    }
+}
+
+/// The bare clone or working tree at `path`, unlike `Repository::open` the parent
+/// directories aren't searched.
+fn open_repository(path: PathBuf) -> Result<Repository, git2::Error> {
+    Repository::open_ext(
+        path,
+        RepositoryOpenFlags::NO_SEARCH,
+        std::iter::empty::<&OsStr>(),
+    )
+}
+
+/// Fetch options with the credentials of `auth`, aborting the transfer once `token` is
+/// cancelled or it took longer than `timeout`.
+///
+/// Only checked while data arrives, [`Git2Backend::run_transfer`] also covers connecting.
+fn fetch_options(
+    auth: AuthConfig,
+    token: CancellationToken,
+    timeout: Duration,
+) -> FetchOptions<'static> {
+    let deadline = Instant::now() + timeout;
+    let mut attempted = false;
+
+    let mut callbacks = RemoteCallbacks::new();
+    callbacks.credentials(move |_url, username, allowed| {
+        // libgit2 asks again after rejected credentials, which would never end
+        if attempted {
+            return Err(git2::Error::from_str("credentials were rejected"));
+        }
+        attempted = true;
+
+        match &auth {
+            AuthConfig::HttpsToken(token)
+                if allowed.contains(CredentialType::USER_PASS_PLAINTEXT) =>
+            {
+                Cred::userpass_plaintext("x-access-token", token)
+            }
+            AuthConfig::Ssh(SshOptions {
+                identity_file: Some(key),
+                ..
+            }) if allowed.contains(CredentialType::SSH_KEY) => {
+                Cred::ssh_key(username.unwrap_or("git"), None, key, None)
+            }
+            _ => Cred::default(),
+        }
+    });
+    callbacks.transfer_progress(move |_| !token.is_cancelled() && Instant::now() < deadline);
+
+    let mut options = FetchOptions::new();
+    options.remote_callbacks(callbacks);
+
+    options
+}
+
+/// Diff between the trees of two revisions, renames are detected like `--find-renames`.
+fn diff_trees<'r>(
+    repository: &'r Repository,
+    c1: &str,
+    c2: &str,
+    path_filters: &[String],
+    diff_algorithm: Option<DiffAlgorithm>,
+) -> Result<Diff<'r>, GitError> {
+    let old_tree = repository.revparse_single(c1)?.peel_to_tree()?;
+    let new_tree = repository.revparse_single(c2)?.peel_to_tree()?;
+
+    let mut options = DiffOptions::new();
+    for filter in path_filters {
+        options.pathspec(filter);
+    }
+    match diff_algorithm {
+        None | Some(DiffAlgorithm::Myers) => {}
+        Some(DiffAlgorithm::Minimal) => {
+            options.minimal(true);
+        }
+        Some(DiffAlgorithm::Patience | DiffAlgorithm::Histogram) => {
+            options.patience(true);
+        }
+    }
+
+    let mut diff =
+        repository.diff_tree_to_tree(Some(&old_tree), Some(&new_tree), Some(&mut options))?;
+    diff.find_similar(Some(DiffFindOptions::new().renames(true)))?;
+
+    for delta in diff.deltas() {
+        if delta.status() == git2::Delta::Renamed {
+            log::debug!(
+                "Renamed {} -> {}",
+                delta.old_file().path().unwrap_or(Path::new("")).display(),
+                delta.new_file().path().unwrap_or(Path::new("")).display()
+            );
+        }
+    }
+
+    Ok(diff)
+}
+
+/// Commit `FETCH_HEAD` points to, the first entry of the file like `git rev-parse`.
+///
+/// A missing or empty file, as after cloning an empty repository, is `None`.
+fn read_fetch_head(repository: &Repository) -> Result<Option<String>, GitError> {
+    let content = match std::fs::read_to_string(repository.path().join("FETCH_HEAD")) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    Ok(content
+        .lines()
+        .next()
+        .and_then(|line| line.split('\t').next())
+        .map(str::trim)
+        .filter(|hash| !hash.is_empty())
+        .map(str::to_string))
+}
+
+#[async_trait::async_trait]
+impl GitBackend for Git2Backend {
+    /// Fetches the remote's `HEAD` into `FETCH_HEAD`.
+    #[instrument(skip(self))]
+    async fn fetch(&self) -> Result<Option<String>, GitError> {
+        self.fetch_refspec("HEAD".to_string()).await?;
+
+        self.get_current_commit_hash_from_fetch_head().await
+    }
+
+    async fn fetch_branch(&self, branch: &str) -> Result<Option<String>, GitError> {
+        self.fetch_refspec(format!(
+            "+refs/heads/{1}:refs/remotes/{0}/{1}",
+            self.remote, branch
+        ))
+        .await?;
+
+        self.get_current_commit_hash_from_rev(&format!("{}/{}", self.remote, branch))
+            .await
+    }
+
+    #[instrument(skip(self))]
+    async fn get_current_commit_hash_from_fetch_head(&self) -> Result<Option<String>, GitError> {
+        self.with_repository(read_fetch_head).await
+    }
+
+    async fn get_current_commit_hash_from_rev(
+        &self,
+        rev: &str,
+    ) -> Result<Option<String>, GitError> {
+        let rev = rev.to_string();
+
+        self.with_repository(move |repository| {
+            match repository
+                .revparse_single(&rev)
+                .and_then(|object| object.peel_to_commit())
+            {
+                Ok(commit) => Ok(Some(commit.id().to_string())),
+                // like `rev-parse --verify`, only an unknown revision isn't an error
+                Err(err)
+                    if matches!(
+                        err.code(),
+                        git2::ErrorCode::NotFound | git2::ErrorCode::Peel
+                    ) =>
+                {
+                    Ok(None)
+                }
+                Err(err) => Err(err.into()),
+            }
+        })
+        .await
+    }
+
+    #[instrument(skip(self))]
+    async fn diff_commits(&self, c1: &str, c2: &str) -> Result<HashSet<DiffAction>, GitError> {
+        let changes = self.diff_commits_raw(c1, c2).await?;
+
+        crates_index::diff_actions(&changes, self.diff_error_mode)
+    }
+
+    async fn diff_stats(&self, c1: &str, c2: &str) -> Result<Vec<FileStat>, GitError> {
+        let (c1, c2) = (c1.to_string(), c2.to_string());
+        let path_filters = self.path_filters.clone();
+        let diff_algorithm = self.diff_algorithm;
+
+        self.with_repository(move |repository| {
+            let diff = diff_trees(repository, &c1, &c2, &path_filters, diff_algorithm)?;
+
+            let mut stats = Vec::new();
+            for i in 0..diff.deltas().len() {
+                let Some(patch) = git2::Patch::from_diff(&diff, i)? else {
+                    continue;
+                };
+                let delta = patch.delta();
+                let path = delta
+                    .new_file()
+                    .path()
+                    .or_else(|| delta.old_file().path())
+                    .map(|path| path.to_string_lossy().into_owned())
+                    .unwrap_or_default();
+
+                let (added, deleted) = if delta.flags().is_binary() {
+                    (None, None)
+                } else {
+                    let (_, added, deleted) = patch.line_stats()?;
+                    (Some(added as u64), Some(deleted as u64))
+                };
+
+                stats.push(FileStat {
+                    path,
+                    added,
+                    deleted,
+                });
+            }
+
+            Ok(stats)
+        })
+        .await
+    }
+
+    async fn get_commit_metadata(&self, rev: &str) -> Result<CommitMeta, GitError> {
+        let rev = rev.to_string();
+
+        self.with_repository(move |repository| {
+            let commit = repository.revparse_single(&rev)?.peel_to_commit()?;
+            let author = commit.author();
+
+            Ok(CommitMeta {
+                hash: commit.id().to_string(),
+                author_name: String::from_utf8_lossy(author.name_bytes()).into_owned(),
+                author_email: String::from_utf8_lossy(author.email_bytes()).into_owned(),
+                committed_at: DateTime::from_timestamp(commit.time().seconds(), 0),
+                subject: String::from_utf8_lossy(commit.summary_bytes().unwrap_or_default())
+                    .into_owned(),
+            })
+        })
+        .await
+    }
+
+    /// An `ancestor` that isn't available locally isn't one.
+    async fn is_ancestor(&self, ancestor: &str, descendant: &str) -> Result<bool, GitError> {
+        let (ancestor, descendant) = (ancestor.to_string(), descendant.to_string());
+
+        self.with_repository(move |repository| {
+            let ancestor = match repository.revparse_single(&ancestor) {
+                Ok(object) => object.peel_to_commit()?.id(),
+                Err(err) if err.code() == git2::ErrorCode::NotFound => return Ok(false),
+                Err(err) => return Err(err.into()),
+            };
+            let descendant = repository
+                .revparse_single(&descendant)?
+                .peel_to_commit()?
+                .id();
+
+            Ok(ancestor == descendant || repository.graph_descendant_of(descendant, ancestor)?)
+        })
+        .await
+    }
+
+    /// Walks the commits of every ref and loads their trees, a missing repository
+    /// directory counts as invalid.
+    async fn verify(&self) -> Result<bool, GitError> {
+        if !tokio::fs::metadata(&self.repository_path)
+            .await
+            .is_ok_and(|meta| meta.is_dir())
+        {
+            return Ok(false);
+        }
+
+        self.with_repository(|repository| {
+            let walk = || -> Result<(), git2::Error> {
+                let mut revwalk = repository.revwalk()?;
+                revwalk.push_glob("*")?;
+                for id in revwalk {
+                    repository.find_commit(id?)?.tree()?;
+                }
+
+                Ok(())
+            };
+
+            Ok(walk().is_ok())
+        })
+        .await
+        .or_else(|err| match err {
+            GitError::Libgit2(err) => {
+                log::warn!("Failed to open repository: {}", err);
+                Ok(false)
+            }
+            err => Err(err),
+        })
+    }
+
+    async fn is_repository(&self) -> Result<bool, GitError> {
+        if !tokio::fs::metadata(&self.repository_path)
+            .await
+            .is_ok_and(|meta| meta.is_dir())
+        {
+            return Ok(false);
+        }
+
+        let path = self.repository_path.clone();
+        self.run(move || match open_repository(path) {
+            Ok(_) => Ok(true),
+            Err(err) if err.code() == git2::ErrorCode::NotFound => Ok(false),
+            // e.g. a repository of another user, which isn't ours to delete
+            Err(err) => Err(err.into()),
+        })
+        .await
+    }
+
+    async fn reclone(&self, git_url: &str, options: &CloneOptions) -> Result<(), GitError> {
+        match tokio::fs::remove_dir_all(&self.repository_path).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        self.clone_repository(git_url, options).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+    use crate::git::GitService;
+    use crate::test_util::{Remote, TempDir, index_line, index_path};
+
+    fn lines(lines: &[String]) -> String {
+        lines.join("\n") + "\n"
+    }
+
+    #[tokio::test]
+    async fn finds_the_same_changes_as_the_cli() {
+        let remote = Remote::new();
+        let (serde, tokio, rand) = (index_path("serde"), index_path("tokio"), index_path("rand"));
+        let mut commits = vec![remote.commit(
+            &[
+                (&serde, &lines(&[index_line("serde", "1.0.0", false)])),
+                (&tokio, &lines(&[index_line("tokio", "1.0.0", false)])),
+                (&rand, &lines(&[index_line("rand", "0.8.0", false)])),
+            ],
+            "initial",
+        )];
+        commits.push(remote.commit(
+            &[(
+                &serde,
+                &lines(&[
+                    index_line("serde", "1.0.0", false),
+                    index_line("serde", "1.0.1", false),
+                ]),
+            )],
+            "add",
+        ));
+        commits.push(remote.commit(
+            &[(&tokio, &lines(&[index_line("tokio", "1.0.0", true)]))],
+            "yank",
+        ));
+        commits.push(remote.commit(
+            &[(&tokio, &lines(&[index_line("tokio", "1.1.0", false)]))],
+            "update",
+        ));
+        std::fs::remove_file(remote.path().join(&rand)).unwrap();
+        commits.push(remote.commit(&[], "remove"));
+        std::fs::rename(remote.path().join(&serde), remote.path().join("serde")).unwrap();
+        commits.push(remote.commit(&[], "rename"));
+
+        let dir = TempDir::new();
+        let cli = GitService::new(dir.path().join("cli"));
+        cli.clone_repository(&remote.url(), &CloneOptions::default())
+            .await
+            .unwrap();
+        let libgit2 = Git2Backend::new(dir.path().join("libgit2"));
+        libgit2
+            .clone_repository(&remote.url(), &CloneOptions::default())
+            .await
+            .unwrap();
+
+        let ranges = commits
+            .windows(2)
+            .map(|range| (&range[0], &range[1]))
+            .chain([(&commits[0], commits.last().unwrap())]);
+        for (c1, c2) in ranges {
+            let expected = cli.diff_commits(c1, c2).await.unwrap();
+            assert_eq!(libgit2.diff_commits(c1, c2).await.unwrap(), expected);
+            assert_eq!(
+                libgit2.diff_stats(c1, c2).await.unwrap(),
+                cli.diff_stats(c1, c2).await.unwrap()
+            );
+        }
+        // every kind of change was found, only the rename finds nothing
+        assert_eq!(
+            cli.diff_commits(&commits[0], commits.last().unwrap())
+                .await
+                .unwrap()
+                .len(),
+            3
+        );
+        assert!(
+            cli.diff_commits(&commits[4], &commits[5])
+                .await
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn times_out_a_connection_that_hangs() {
+        // accepts the connection, but never answers
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/index.git", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || listener.accept().unwrap().0);
+
+        let dir = TempDir::new();
+        let backend = Git2Backend::new(dir.path().join("clone"))
+            .with_command_timeout(Duration::from_millis(300));
+        let result = backend
+            .clone_repository(&url, &CloneOptions::default())
+            .await;
+
+        assert!(matches!(result, Err(GitError::Timeout(_))), "{:?}", result);
+        // closing the connection lets the abandoned clone end
+        drop(server.join().unwrap());
+    }
+}
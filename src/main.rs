@@ -1,41 +1,452 @@
-use std::time::Duration;
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
 
+use clap::{Parser, ValueEnum};
+use metrics_exporter_prometheus::PrometheusBuilder;
 use ractor::Actor;
-use tokio::time::Duration as TDuration;
+use tokio::{net::TcpListener, sync::broadcast};
+use tokio_util::sync::CancellationToken;
 
+use tracing::log;
 use tracing_subscriber::EnvFilter;
+use tracing_subscriber::Layer;
+use tracing_subscriber::fmt::MakeWriter;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 
 use crate::actor::{IndexerActor, IndexerActorArguments, IndexerActorMessage};
+use crate::config::Config;
+use crate::git::CloneOptions;
+use crate::server::ServerState;
+use crate::supervisor::{SupervisorActor, SupervisorMessage};
 
 pub mod actor;
+pub mod config;
+pub mod crates_index;
 pub mod git;
+#[cfg(feature = "libgit2")]
+pub mod git2_backend;
+pub mod server;
+pub mod state;
+#[cfg(feature = "sqlite")]
+pub mod store;
+pub mod supervisor;
+#[cfg(test)]
+mod test_util;
+pub mod webhook;
+
+#[derive(Debug, Clone, Copy, Default, ValueEnum)]
+enum LogFormat {
+    /// Human readable lines.
+    #[default]
+    Text,
+    /// One JSON object per line, including the fields of the surrounding spans.
+    Json,
+}
+
+/// Clones a git repository and periodically checks it for new commits.
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Cli {
+    /// Url of the repository to poll.
+    #[arg(
+        long,
+        default_value = "https://github.com/rust-lang/crates.io-index.git"
+    )]
+    git_url: String,
+
+    /// Directory to clone into, derived from the url if not set.
+    #[arg(long)]
+    dir_name: Option<String>,
+
+    /// Seconds between two polls.
+    #[arg(long, default_value_t = 25, value_parser = clap::value_parser!(u64).range(1..))]
+    interval: u64,
+
+    /// Only clone the last `n` commits, deepened when a diff needs older ones.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    clone_depth: Option<u32>,
+
+    /// Index a single time and exit.
+    #[arg(long)]
+    once: bool,
+
+    /// TOML file listing the repositories to poll, replaces the repository flags.
+    #[arg(long, conflicts_with_all = ["git_url", "dir_name", "interval", "clone_depth"])]
+    config: Option<PathBuf>,
+
+    /// Directory to clone into, overrides the one of the config.
+    #[arg(long)]
+    base_dir: Option<PathBuf>,
+
+    /// Git executable to run, overrides the one of the config.
+    #[arg(long)]
+    git_binary: Option<PathBuf>,
+
+    /// Delete the clones when shutting down.
+    #[arg(long)]
+    cleanup_on_stop: bool,
+
+    /// Log the changes that would be indexed without saving state, recording or calling
+    /// the webhook.
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Log filter directives like `info,actor_http_test::git=warn`, overrides `RUST_LOG`.
+    #[arg(long)]
+    log_level: Option<String>,
+
+    /// Format of the log output.
+    #[arg(long, value_enum, default_value_t)]
+    log_format: LogFormat,
+
+    /// Address to serve Prometheus metrics on, e.g. `127.0.0.1:9000`.
+    #[arg(long)]
+    metrics_listen: Option<SocketAddr>,
+
+    /// Address to serve the `/events` WebSocket and, with `--database`, `/changes` on.
+    #[arg(long)]
+    http_listen: Option<SocketAddr>,
+
+    /// SQLite database every detected change of all repositories is recorded in.
+    #[cfg(feature = "sqlite")]
+    #[arg(long)]
+    database: Option<PathBuf>,
+}
+
+/// Log filter used when neither `--log-level` nor `RUST_LOG` is set.
+const DEFAULT_LOG_FILTER: &str = "info";
+
+/// Filter from `log_level`, falling back to `RUST_LOG` and then [`DEFAULT_LOG_FILTER`].
+fn env_filter(log_level: Option<&str>) -> EnvFilter {
+    match log_level {
+        Some(directives) => EnvFilter::builder().parse_lossy(directives),
+        None => EnvFilter::try_from_default_env()
+            .unwrap_or_else(|_| EnvFilter::builder().parse_lossy(DEFAULT_LOG_FILTER)),
+    }
+}
+
+/// Layer writing the log lines in `log_format` to `writer`.
+fn fmt_layer<S, W>(log_format: LogFormat, writer: W) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    match log_format {
+        LogFormat::Text => tracing_subscriber::fmt::layer().with_writer(writer).boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer()
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .with_writer(writer)
+            .boxed(),
+    }
+}
+
+/// Runs a single index of the repository in its own actor, for `--once`.
+async fn index_with_actor(arguments: IndexerActorArguments) -> Result<(), String> {
+    let (indexer_actor, indexer_handle) = Actor::spawn(None, IndexerActor, arguments)
+        .await
+        .map_err(|e| format!("failed to start the indexer: {}", e))?;
+
+    // messages are handled in order, so the reply arrives once the index finished
+    let status = match indexer_actor.cast(IndexerActorMessage::Index) {
+        Ok(()) => ractor::call!(indexer_actor, IndexerActorMessage::GetStatus)
+            .map_err(|e| format!("the indexer didn't reply: {}", e)),
+        Err(e) => Err(format!("failed to start the index: {}", e)),
+    };
+
+    indexer_actor.stop(None);
+    if let Err(e) = indexer_handle.await {
+        log::warn!("Indexer didn't stop cleanly: {}", e);
+    }
+
+    let status = status?;
+    if status.consecutive_failures > 0 {
+        return Err("the index failed".to_string());
+    }
+    log::info!("Indexed, current commit: {:?}", status.last_commit_hash);
+
+    Ok(())
+}
 
 #[tokio::main]
 async fn main() {
+    let cli = Cli::parse();
+
     tracing_subscriber::registry()
-        .with(tracing_subscriber::fmt::layer())
-        .with(EnvFilter::builder().parse_lossy("debug"))
+        .with(fmt_layer(cli.log_format, std::io::stdout))
+        .with(env_filter(cli.log_level.as_deref()))
         .init();
 
-    let (indexer_actor, indexer_handle) = Actor::spawn(
+    if let Some(address) = cli.metrics_listen {
+        if let Err(e) = PrometheusBuilder::new()
+            .with_http_listener(address)
+            .install()
+        {
+            log::error!("Failed to start metrics endpoint on {}: {}", address, e);
+            std::process::exit(1);
+        }
+
+        log::info!("Serving metrics on http://{}/metrics", address);
+    }
+
+    let repositories = match &cli.config {
+        Some(path) => match Config::load(path).await {
+            Ok(config) => config.indexer_arguments(),
+            Err(e) => {
+                log::error!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        None => vec![(
+            IndexerActorArguments::new(cli.git_url, cli.dir_name).with_clone_options(
+                CloneOptions {
+                    clone_depth: cli.clone_depth,
+                    ..CloneOptions::default()
+                },
+            ),
+            Duration::from_secs(cli.interval),
+        )],
+    };
+
+    // flags shared by all repositories
+    let cancellation_token = CancellationToken::new();
+    let events = cli
+        .http_listen
+        .map(|_| broadcast::channel(server::EVENTS_CAPACITY).0);
+    let repositories = repositories
+        .into_iter()
+        .map(|(mut arguments, interval)| {
+            if let Some(base_dir) = &cli.base_dir {
+                arguments = arguments.with_base_dir(base_dir.clone());
+            }
+            if let Some(git_binary) = &cli.git_binary {
+                arguments = arguments.with_git_binary(git_binary.clone());
+            }
+            if let Some(events) = &events {
+                arguments = arguments.with_broadcast(events.clone());
+            }
+            #[cfg(feature = "sqlite")]
+            if let Some(database) = &cli.database {
+                arguments = arguments.with_database(database.clone());
+            }
+
+            (
+                arguments
+                    .with_cancellation_token(cancellation_token.clone())
+                    .with_cleanup_on_stop(cli.cleanup_on_stop)
+                    .with_dry_run(cli.dry_run),
+                interval,
+            )
+        })
+        .collect::<Vec<_>>();
+
+    if let (Some(address), Some(events)) = (cli.http_listen, events) {
+        let state = ServerState::new(events);
+        #[cfg(feature = "sqlite")]
+        let state = match &cli.database {
+            Some(database) => match store::DiffStore::open(database).await {
+                Ok(store) => state.with_store(store),
+                Err(e) => {
+                    log::error!("Failed to open database {:?}: {:?}", database, e);
+                    std::process::exit(1);
+                }
+            },
+            None => state,
+        };
+
+        let listener = match TcpListener::bind(address).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                log::error!("Failed to start http endpoint on {}: {}", address, e);
+                std::process::exit(1);
+            }
+        };
+        log::info!("Serving http on http://{}", address);
+
+        let shutdown = cancellation_token.clone();
+        tokio::spawn(async move {
+            if let Err(e) = server::serve(listener, state, shutdown).await {
+                log::error!("Http endpoint on {} failed: {}", address, e);
+            }
+        });
+    }
+
+    if cli.once {
+        let mut failed = false;
+        for (arguments, _) in repositories {
+            let name = arguments.dir_name();
+            if let Err(e) = index_with_actor(arguments).await {
+                log::error!("Failed to index {}: {}", name, e);
+                failed = true;
+            }
+        }
+
+        if failed {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let intervals = repositories
+        .iter()
+        .map(|(arguments, interval)| (arguments.dir_name(), *interval))
+        .collect::<Vec<_>>();
+
+    let (supervisor, supervisor_handle) = Actor::spawn(
         None,
-        IndexerActor,
-        IndexerActorArguments::new(
-            "https://github.com/rust-lang/crates.io-index.git".to_string(),
-            None,
-        ),
+        SupervisorActor,
+        repositories
+            .into_iter()
+            .map(|(arguments, _)| arguments)
+            .collect(),
     )
     .await
     .unwrap();
 
-    indexer_actor
-        .cast(IndexerActorMessage::StartAutoIndex(Duration::from_secs(25)))
+    for (name, interval) in intervals {
+        supervisor
+            .cast(SupervisorMessage::StartAutoIndex { name, interval })
+            .unwrap();
+    }
+
+    tokio::signal::ctrl_c().await.unwrap();
+    log::info!("Received ctrl-c, shutting down");
+
+    // abort running git commands, stopping waits for the current message to finish
+    cancellation_token.cancel();
+
+    supervisor.stop(None);
+    supervisor_handle.await.unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    #[test]
+    fn parses_defaults() {
+        let cli = Cli::try_parse_from(["poll"]).unwrap();
+
+        assert_eq!(
+            cli.git_url,
+            "https://github.com/rust-lang/crates.io-index.git"
+        );
+        assert_eq!(cli.dir_name, None);
+        assert_eq!(cli.interval, 25);
+        assert!(!cli.once);
+        assert_eq!(cli.clone_depth, None);
+        assert_eq!(cli.config, None);
+    }
+
+    #[test]
+    fn parses_once() {
+        let cli = Cli::try_parse_from([
+            "poll",
+            "--git-url",
+            "https://example.com/index.git",
+            "--dir-name",
+            "index",
+            "--interval",
+            "60",
+            "--once",
+        ])
         .unwrap();
 
-    tokio::time::sleep(TDuration::from_mins(2)).await;
+        assert_eq!(cli.git_url, "https://example.com/index.git");
+        assert_eq!(cli.dir_name.as_deref(), Some("index"));
+        assert_eq!(cli.interval, 60);
+        assert!(cli.once);
+    }
 
-    indexer_actor.stop(None);
-    indexer_handle.await.unwrap();
+    #[test]
+    fn rejects_a_zero_interval() {
+        assert!(Cli::try_parse_from(["poll", "--interval", "0"]).is_err());
+    }
+
+    #[test]
+    fn parses_the_clone_depth() {
+        let cli = Cli::try_parse_from(["poll", "--clone-depth", "1"]).unwrap();
+
+        assert_eq!(cli.clone_depth, Some(1));
+        assert!(Cli::try_parse_from(["poll", "--clone-depth", "0"]).is_err());
+    }
+
+    /// Log output written to memory.
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl Captured {
+        fn output(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    impl std::io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn filters_by_the_explicit_directives() {
+        let captured = Captured::default();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_subscriber::fmt::layer().with_writer({
+                let captured = captured.clone();
+                move || captured.clone()
+            }))
+            .with(env_filter(Some("warn,actor_http_test::git=debug")));
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::debug!(target: "actor_http_test::git", "git debug");
+            tracing::info!(target: "ractor", "ractor info");
+            tracing::warn!(target: "ractor", "ractor warning");
+        });
+
+        let output = captured.output();
+        assert!(output.contains("git debug"), "{}", output);
+        assert!(!output.contains("ractor info"), "{}", output);
+        assert!(output.contains("ractor warning"), "{}", output);
+    }
+
+    #[test]
+    fn parses_the_log_level() {
+        let cli = Cli::try_parse_from(["poll", "--log-level", "info,ractor=warn"]).unwrap();
+
+        assert_eq!(cli.log_level.as_deref(), Some("info,ractor=warn"));
+        assert_eq!(Cli::try_parse_from(["poll"]).unwrap().log_level, None);
+    }
+
+    #[test]
+    fn logs_json_with_the_span_fields() {
+        let captured = Captured::default();
+        let subscriber = tracing_subscriber::registry().with(fmt_layer(LogFormat::Json, {
+            let captured = captured.clone();
+            move || captured.clone()
+        }));
+
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("index", repository = "index");
+            let _entered = span.enter();
+            tracing::info!(new_commit = "c2", "Indexed");
+        });
+
+        let output = captured.output();
+        let lines = output.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 1, "{}", output);
+        let json: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(json["fields"]["message"], "Indexed");
+        assert_eq!(json["fields"]["new_commit"], "c2");
+        assert_eq!(json["span"]["name"], "index");
+        assert_eq!(json["span"]["repository"], "index");
+        assert_eq!(json["spans"][0]["name"], "index");
+    }
 }
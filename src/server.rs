@@ -0,0 +1,392 @@
+#[cfg(feature = "sqlite")]
+use axum::{Json, extract::Query, http::StatusCode};
+use axum::{
+    Router,
+    extract::{
+        State,
+        ws::{Message, WebSocket, WebSocketUpgrade},
+    },
+    response::Response,
+    routing::get,
+};
+#[cfg(feature = "sqlite")]
+use serde::Deserialize;
+use tokio::{
+    net::TcpListener,
+    sync::broadcast::{self, error::RecvError},
+};
+use tokio_util::sync::CancellationToken;
+use tracing::log;
+
+use crate::actor::DiffBatch;
+#[cfg(feature = "sqlite")]
+use crate::store::{ChangeQuery, DiffStore, StoredAction};
+
+/// Batches buffered per WebSocket client before a slow one starts missing them.
+pub const EVENTS_CAPACITY: usize = 256;
+
+/// Changes returned by `GET /changes` without a `limit`.
+#[cfg(feature = "sqlite")]
+pub const DEFAULT_PAGE_SIZE: u32 = 100;
+
+/// Upper bound of the `limit` of `GET /changes`.
+#[cfg(feature = "sqlite")]
+pub const MAX_PAGE_SIZE: u32 = 1000;
+
+/// What the handlers of [`router`] serve from.
+#[derive(Clone)]
+pub struct ServerState {
+    events: broadcast::Sender<DiffBatch>,
+    #[cfg(feature = "sqlite")]
+    store: Option<DiffStore>,
+}
+
+impl ServerState {
+    pub fn new(events: broadcast::Sender<DiffBatch>) -> Self {
+        Self {
+            events,
+            #[cfg(feature = "sqlite")]
+            store: None,
+        }
+    }
+
+    /// Serves the recorded changes of `store` on `GET /changes`.
+    #[cfg(feature = "sqlite")]
+    pub fn with_store(mut self, store: DiffStore) -> Self {
+        self.store = Some(store);
+        self
+    }
+}
+
+/// Routes of the HTTP server.
+///
+/// `GET /events` upgrades to a WebSocket that receives every [`DiffBatch`] published to
+/// the events channel as a JSON text frame. With a store, `GET /changes` queries the
+/// recorded changes, see [`ChangesParams`].
+pub fn router(state: ServerState) -> Router {
+    let router = Router::new().route("/events", get(events_handler));
+    #[cfg(feature = "sqlite")]
+    let router = router.route("/changes", get(changes_handler));
+
+    router.with_state(state)
+}
+
+/// Serves [`router`] on `listener` until `shutdown` is cancelled.
+pub async fn serve(
+    listener: TcpListener,
+    state: ServerState,
+    shutdown: CancellationToken,
+) -> Result<(), std::io::Error> {
+    axum::serve(listener, router(state))
+        .with_graceful_shutdown(shutdown.cancelled_owned())
+        .await
+}
+
+async fn events_handler(ws: WebSocketUpgrade, State(state): State<ServerState>) -> Response {
+    let events = state.events.subscribe();
+
+    ws.on_upgrade(move |socket| stream_events(socket, events))
+}
+
+/// Query string of `GET /changes`, every filter is optional.
+#[cfg(feature = "sqlite")]
+#[derive(Debug, Deserialize)]
+pub struct ChangesParams {
+    /// Name of the repository, its directory name.
+    repo: Option<String>,
+    /// Only changes recorded at or after these seconds since the unix epoch.
+    since: Option<i64>,
+    #[serde(rename = "crate")]
+    crate_name: Option<String>,
+    /// [`DEFAULT_PAGE_SIZE`] if unset, at most [`MAX_PAGE_SIZE`].
+    limit: Option<u32>,
+    #[serde(default)]
+    offset: u32,
+}
+
+/// Recorded changes matching the query, newest first.
+#[cfg(feature = "sqlite")]
+async fn changes_handler(
+    State(state): State<ServerState>,
+    Query(params): Query<ChangesParams>,
+) -> Result<Json<Vec<StoredAction>>, (StatusCode, &'static str)> {
+    let Some(store) = &state.store else {
+        return Err((StatusCode::NOT_FOUND, "no database configured"));
+    };
+
+    let query = ChangeQuery {
+        repository: params.repo,
+        since: params.since,
+        crate_name: params.crate_name,
+        limit: params.limit.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE),
+        offset: params.offset,
+    };
+
+    match store.query(query).await {
+        Ok(changes) => Ok(Json(changes)),
+        Err(e) => {
+            log::error!("Failed to query changes: {:?}", e);
+            Err((StatusCode::INTERNAL_SERVER_ERROR, "failed to query changes"))
+        }
+    }
+}
+
+/// Forwards batches to the client until it disconnects.
+///
+/// A client that falls more than [`EVENTS_CAPACITY`] batches behind skips the missed ones.
+async fn stream_events(mut socket: WebSocket, mut events: broadcast::Receiver<DiffBatch>) {
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(batch) => {
+                    let json = match serde_json::to_string(&batch) {
+                        Ok(json) => json,
+                        Err(e) => {
+                            log::error!("Failed to serialize batch {}: {}", batch.new_commit, e);
+                            continue;
+                        }
+                    };
+                    if socket.send(Message::Text(json.into())).await.is_err() {
+                        break;
+                    }
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    log::warn!("WebSocket client lagged behind, skipped {} batches", skipped);
+                }
+                Err(RecvError::Closed) => break,
+            },
+            // only closing matters, anything else the client sends is ignored
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+
+    log::debug!("WebSocket client disconnected");
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+    use futures_util::StreamExt;
+    use ractor::Actor;
+    use tokio_tungstenite::tungstenite;
+
+    use super::*;
+    use crate::actor::{IndexerActor, IndexerActorArguments, IndexerActorMessage};
+    use crate::crates_index::{CrateChange, DiffAction};
+    use crate::test_util::{MockBackend, TempDir};
+
+    /// Serves [`router`] on a free port until the token is cancelled.
+    async fn start(state: ServerState) -> (SocketAddr, CancellationToken) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let shutdown = CancellationToken::new();
+        tokio::spawn(serve(listener, state, shutdown.clone()));
+
+        (address, shutdown)
+    }
+
+    type Client = tokio_tungstenite::WebSocketStream<
+        tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
+    >;
+
+    /// Next text frame of `client` as JSON.
+    async fn next_json(client: &mut Client) -> serde_json::Value {
+        let message = tokio::time::timeout(Duration::from_secs(10), client.next())
+            .await
+            .expect("no frame arrived")
+            .unwrap()
+            .unwrap();
+
+        match message {
+            tungstenite::Message::Text(text) => serde_json::from_str(&text).unwrap(),
+            message => panic!("unexpected frame {:?}", message),
+        }
+    }
+
+    #[tokio::test]
+    async fn streams_the_batches_of_an_index_to_every_client() {
+        let (events, _) = broadcast::channel(EVENTS_CAPACITY);
+        let (address, shutdown) = start(ServerState::new(events.clone())).await;
+        let url = format!("ws://{}/events", address);
+        let (mut first, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        let (mut second, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        let (gone, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        // the handlers subscribe once the upgrade finished
+        while events.receiver_count() < 3 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        drop(gone);
+
+        let base_dir = TempDir::new();
+        let backend = Arc::new(MockBackend::new("c1"));
+        let serde = DiffAction::Add(CrateChange::new("serde", Some("1.0.0".to_string())));
+        backend.push("c1", "c2", [serde]);
+        let arguments = IndexerActorArguments::new(
+            "https://example.com/index.git".to_string(),
+            Some("index".to_string()),
+        )
+        .with_base_dir(base_dir.path().to_path_buf())
+        .with_backend(backend.clone())
+        .with_broadcast(events);
+        let (actor, handle) = Actor::spawn(None, IndexerActor, arguments).await.unwrap();
+        actor.cast(IndexerActorMessage::Index).unwrap();
+
+        for client in [&mut first, &mut second] {
+            let batch = next_json(client).await;
+            assert_eq!(batch["repository"], "index");
+            assert_eq!(batch["old_commit"], "c1");
+            assert_eq!(batch["new_commit"], "c2");
+            assert_eq!(batch["actions"].as_array().unwrap().len(), 1);
+        }
+
+        actor.stop(None);
+        handle.await.unwrap();
+        shutdown.cancel();
+    }
+
+    fn batch(new_commit: &str) -> DiffBatch {
+        DiffBatch {
+            repository: "index".to_string(),
+            old_commit: None,
+            new_commit: new_commit.to_string(),
+            actions: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn skips_the_batches_a_slow_client_missed() {
+        let (events, _) = broadcast::channel(1);
+        let (address, shutdown) = start(ServerState::new(events.clone())).await;
+        let (mut client, _) = tokio_tungstenite::connect_async(format!("ws://{}/events", address))
+            .await
+            .unwrap();
+        while events.receiver_count() < 1 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // sent without yielding, so the handler only gets to the channel once it lagged
+        for commit in ["c1", "c2", "c3"] {
+            events.send(batch(commit)).unwrap();
+        }
+        assert_eq!(next_json(&mut client).await["new_commit"], "c3");
+
+        // still connected
+        events.send(batch("c4")).unwrap();
+        assert_eq!(next_json(&mut client).await["new_commit"], "c4");
+
+        shutdown.cancel();
+    }
+
+    /// Status and JSON body of `GET /changes?<query>`.
+    #[cfg(feature = "sqlite")]
+    async fn get_changes(address: SocketAddr, query: &str) -> (StatusCode, serde_json::Value) {
+        let response = reqwest::get(format!("http://{}/changes?{}", address, query))
+            .await
+            .unwrap();
+        let status = StatusCode::from_u16(response.status().as_u16()).unwrap();
+        let body = response.text().await.unwrap();
+
+        (status, serde_json::from_str(&body).unwrap_or_default())
+    }
+
+    /// `field` of every change in `changes`.
+    #[cfg(feature = "sqlite")]
+    fn fields<'a>(changes: &'a serde_json::Value, field: &str) -> Vec<&'a str> {
+        changes
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|change| change[field].as_str().unwrap())
+            .collect()
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn filters_and_pages_the_recorded_changes() {
+        let dir = TempDir::new();
+        let store = DiffStore::open(&dir.path().join("changes.db"))
+            .await
+            .unwrap();
+        let add = |name: &str, version: &str| {
+            DiffAction::Add(CrateChange::new(name, Some(version.to_string())))
+        };
+        for (repository, commit, actions) in [
+            (
+                "a",
+                "c1",
+                vec![add("serde", "1.0.0"), add("tokio", "1.0.0")],
+            ),
+            (
+                "b",
+                "c2",
+                vec![DiffAction::Update(CrateChange::new(
+                    "serde",
+                    Some("1.0.1".to_string()),
+                ))],
+            ),
+            ("a", "c3", vec![add("rand", "0.9.0")]),
+        ] {
+            store
+                .insert_batch(DiffBatch {
+                    repository: repository.to_string(),
+                    old_commit: None,
+                    new_commit: commit.to_string(),
+                    actions,
+                })
+                .await
+                .unwrap();
+        }
+        let (events, _) = broadcast::channel(EVENTS_CAPACITY);
+        let (address, shutdown) = start(ServerState::new(events).with_store(store)).await;
+
+        let (status, all) = get_changes(address, "").await;
+        assert_eq!(status, StatusCode::OK);
+        // newest first
+        assert_eq!(fields(&all, "name"), ["rand", "serde", "tokio", "serde"]);
+        assert_eq!(all[1]["kind"], "update");
+        assert_eq!(all[1]["version"], "1.0.1");
+
+        let names = |query: &'static str| async move {
+            let (status, changes) = get_changes(address, query).await;
+            assert_eq!(status, StatusCode::OK, "{}", query);
+            fields(&changes, "name")
+                .into_iter()
+                .map(str::to_string)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names("repo=a").await, ["rand", "tokio", "serde"]);
+        assert_eq!(names("crate=tokio").await, ["tokio"]);
+        assert_eq!(names("limit=2").await, ["rand", "serde"]);
+        assert_eq!(names("limit=2&offset=2").await, ["tokio", "serde"]);
+        assert_eq!(names("since=0").await.len(), 4);
+        let later = chrono::Utc::now().timestamp() + 3600;
+        let (_, changes) = get_changes(address, &format!("since={}", later)).await;
+        assert_eq!(changes, serde_json::json!([]));
+
+        let (_, serde) = get_changes(address, "crate=serde").await;
+        assert_eq!(fields(&serde, "commit_hash"), ["c2", "c1"]);
+        let (_, serde) = get_changes(address, "crate=serde&repo=a").await;
+        assert_eq!(fields(&serde, "repository"), ["a"]);
+
+        let (status, _) = get_changes(address, "limit=many").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        shutdown.cancel();
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn has_no_changes_without_a_store() {
+        let (events, _) = broadcast::channel(EVENTS_CAPACITY);
+        let (address, shutdown) = start(ServerState::new(events)).await;
+
+        let (status, _) = get_changes(address, "").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        shutdown.cancel();
+    }
+}
@@ -0,0 +1,77 @@
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use tracing::log;
+
+/// State of an indexer that survives restarts.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct PollState {
+    pub last_commit_hash: Option<String>,
+    /// Range of the last emitted batch, a range is only emitted once.
+    #[serde(default)]
+    pub last_emitted: Option<EmittedRange>,
+}
+
+/// Commits a batch of actions was diffed between, see [`crate::actor::DiffBatch`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EmittedRange {
+    pub old_commit: Option<String>,
+    pub new_commit: String,
+}
+
+impl EmittedRange {
+    pub fn new(old_commit: Option<&str>, new_commit: &str) -> Self {
+        Self {
+            old_commit: old_commit.map(str::to_string),
+            new_commit: new_commit.to_string(),
+        }
+    }
+
+    pub fn is(&self, old_commit: Option<&str>, new_commit: &str) -> bool {
+        self.old_commit.as_deref() == old_commit && self.new_commit == new_commit
+    }
+}
+
+impl PollState {
+    /// Path of the state file, placed next to the repository directory.
+    pub fn path_for(repository_path: &Path) -> PathBuf {
+        let mut file_name = repository_path
+            .file_name()
+            .map(|name| name.to_os_string())
+            .unwrap_or_default();
+        file_name.push(".poll-state.json");
+
+        repository_path.with_file_name(file_name)
+    }
+
+    /// Loads the state, returns `None` if the file is missing or can't be parsed.
+    pub async fn load(path: &Path) -> Option<Self> {
+        let content = match tokio::fs::read(path).await {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+            Err(e) => {
+                log::warn!("Failed to read state file {}: {}", path.display(), e);
+                return None;
+            }
+        };
+
+        match serde_json::from_slice(&content) {
+            Ok(state) => Some(state),
+            Err(e) => {
+                log::warn!("Ignoring corrupt state file {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    /// Writes the state to a temporary file first, so a crash never leaves a half-written file.
+    pub async fn save(&self, path: &Path) -> Result<(), std::io::Error> {
+        let content = serde_json::to_vec_pretty(self)?;
+
+        let mut tmp_path = path.as_os_str().to_os_string();
+        tmp_path.push(".tmp");
+
+        tokio::fs::write(&tmp_path, content).await?;
+        tokio::fs::rename(&tmp_path, path).await
+    }
+}
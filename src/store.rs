@@ -0,0 +1,316 @@
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use rusqlite::{Connection, Params, Statement, params};
+use serde::Serialize;
+
+use crate::actor::DiffBatch;
+use crate::crates_index::{CrateChange, DiffAction};
+
+/// Migrations applied in order, the index of the last applied one is stored in `user_version`.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE diff_actions (
+        id INTEGER PRIMARY KEY,
+        repository TEXT NOT NULL,
+        commit_hash TEXT NOT NULL,
+        kind TEXT NOT NULL,
+        crate_name TEXT NOT NULL,
+        recorded_at INTEGER NOT NULL
+    );
+    CREATE INDEX diff_actions_crate_name ON diff_actions (crate_name);",
+    "ALTER TABLE diff_actions ADD COLUMN crate_version TEXT;",
+    "ALTER TABLE diff_actions ADD COLUMN yanked INTEGER;",
+];
+
+#[derive(Debug)]
+pub enum StoreError {
+    Sqlite(rusqlite::Error),
+    UnknownKind(String),
+    TaskError(tokio::task::JoinError),
+}
+
+impl From<rusqlite::Error> for StoreError {
+    fn from(err: rusqlite::Error) -> Self {
+        StoreError::Sqlite(err)
+    }
+}
+
+impl From<tokio::task::JoinError> for StoreError {
+    fn from(err: tokio::task::JoinError) -> Self {
+        StoreError::TaskError(err)
+    }
+}
+
+/// A [`DiffAction`] as recorded in the store.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct StoredAction {
+    pub repository: String,
+    pub commit_hash: String,
+    #[serde(flatten)]
+    pub action: DiffAction,
+    /// Seconds since the unix epoch.
+    pub recorded_at: i64,
+}
+
+/// SQLite database the indexer records its [`DiffAction`]s in.
+///
+/// The connection is blocking, so every query runs on the blocking thread pool.
+#[derive(Debug, Clone)]
+pub struct DiffStore {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl DiffStore {
+    /// Opens (or creates) the database and applies missing migrations.
+    pub async fn open(path: &Path) -> Result<Self, StoreError> {
+        let path = path.to_path_buf();
+
+        let connection = tokio::task::spawn_blocking(move || {
+            let mut connection = Connection::open(path)?;
+            migrate(&mut connection)?;
+
+            Ok::<_, StoreError>(connection)
+        })
+        .await??;
+
+        Ok(Self {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    async fn with_connection<T, F>(&self, f: F) -> Result<T, StoreError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T, StoreError> + Send + 'static,
+    {
+        let connection = self.connection.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let mut connection = connection.lock().unwrap_or_else(|e| e.into_inner());
+            f(&mut connection)
+        })
+        .await?
+    }
+
+    /// Records all actions of one index cycle in a single transaction, under the batch's
+    /// new commit.
+    pub async fn insert_batch(&self, batch: DiffBatch) -> Result<(), StoreError> {
+        let recorded_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs() as i64)
+            .unwrap_or_default();
+
+        self.with_connection(move |connection| {
+            let transaction = connection.transaction()?;
+            {
+                let mut statement = transaction.prepare(
+                    "INSERT INTO diff_actions (
+                        repository, commit_hash, kind, crate_name, crate_version, yanked,
+                        recorded_at
+                    )
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                )?;
+
+                for action in &batch.actions {
+                    statement.execute(params![
+                        batch.repository,
+                        batch.new_commit,
+                        action.kind(),
+                        action.name(),
+                        action.version(),
+                        action.yanked(),
+                        recorded_at
+                    ])?;
+                }
+            }
+            transaction.commit()?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    /// All recorded actions for `crate_name`, oldest first.
+    pub async fn history(&self, crate_name: &str) -> Result<Vec<StoredAction>, StoreError> {
+        let crate_name = crate_name.to_string();
+
+        self.with_connection(move |connection| {
+            let mut statement = connection.prepare(&format!(
+                "{} WHERE crate_name = ?1 ORDER BY id",
+                SELECT_ACTIONS
+            ))?;
+
+            read_actions(&mut statement, params![crate_name])
+        })
+        .await
+    }
+
+    /// Recorded actions matching every filter of `query`, newest first.
+    pub async fn query(&self, query: ChangeQuery) -> Result<Vec<StoredAction>, StoreError> {
+        self.with_connection(move |connection| {
+            let mut statement = connection.prepare(&format!(
+                "{} WHERE (?1 IS NULL OR repository = ?1)
+                    AND (?2 IS NULL OR recorded_at >= ?2)
+                    AND (?3 IS NULL OR crate_name = ?3)
+                ORDER BY id DESC LIMIT ?4 OFFSET ?5",
+                SELECT_ACTIONS
+            ))?;
+
+            read_actions(
+                &mut statement,
+                params![
+                    query.repository,
+                    query.since,
+                    query.crate_name,
+                    query.limit,
+                    query.offset
+                ],
+            )
+        })
+        .await
+    }
+}
+
+/// Filters of [`DiffStore::query`], unset filters match everything.
+#[derive(Debug, Clone, Default)]
+pub struct ChangeQuery {
+    pub repository: Option<String>,
+    /// Only actions recorded at or after these seconds since the unix epoch.
+    pub since: Option<i64>,
+    pub crate_name: Option<String>,
+    pub limit: u32,
+    pub offset: u32,
+}
+
+/// Columns [`read_actions`] expects, in order.
+const SELECT_ACTIONS: &str = "SELECT repository, commit_hash, kind, crate_name, crate_version,
+    yanked, recorded_at
+    FROM diff_actions";
+
+fn read_actions(
+    statement: &mut Statement,
+    params: impl Params,
+) -> Result<Vec<StoredAction>, StoreError> {
+    let rows = statement.query_map(params, |row| {
+        Ok((
+            row.get::<_, String>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, String>(2)?,
+            row.get::<_, String>(3)?,
+            row.get::<_, Option<String>>(4)?,
+            row.get::<_, Option<bool>>(5)?,
+            row.get::<_, i64>(6)?,
+        ))
+    })?;
+
+    rows.map(|row| {
+        let (repository, commit_hash, kind, name, version, yanked, recorded_at) = row?;
+        let action = DiffAction::from_kind(&kind, CrateChange::new(name, version), yanked)
+            .ok_or_else(|| StoreError::UnknownKind(kind))?;
+
+        Ok(StoredAction {
+            repository,
+            commit_hash,
+            action,
+            recorded_at,
+        })
+    })
+    .collect()
+}
+
+fn migrate(connection: &mut Connection) -> Result<(), StoreError> {
+    let version: u32 = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+
+    let transaction = connection.transaction()?;
+    for migration in MIGRATIONS.iter().skip(version as usize) {
+        transaction.execute_batch(migration)?;
+    }
+    transaction.pragma_update(None, "user_version", MIGRATIONS.len() as u32)?;
+    transaction.commit()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    fn change(name: &str, version: &str) -> CrateChange {
+        CrateChange::new(name, Some(version.to_string()))
+    }
+
+    #[tokio::test]
+    async fn reads_back_the_history_of_a_crate() {
+        let dir = TempDir::new();
+        let path = dir.path().join("changes.db");
+        let store = DiffStore::open(&path).await.unwrap();
+        let actions = vec![
+            DiffAction::Add(change("serde", "1.0.0")),
+            DiffAction::Remove(change("tokio", "1.0.0")),
+            DiffAction::YankChange {
+                change: change("serde", "0.9.0"),
+                yanked: true,
+            },
+        ];
+
+        store
+            .insert_batch(DiffBatch {
+                repository: "index".to_string(),
+                old_commit: Some("c1".to_string()),
+                new_commit: "c2".to_string(),
+                actions: actions.clone(),
+            })
+            .await
+            .unwrap();
+        store
+            .insert_batch(DiffBatch {
+                repository: "index".to_string(),
+                old_commit: Some("c2".to_string()),
+                new_commit: "c3".to_string(),
+                actions: vec![DiffAction::Update(change("serde", "1.0.1"))],
+            })
+            .await
+            .unwrap();
+
+        // survives reopening, the migrations aren't applied twice
+        drop(store);
+        let history = DiffStore::open(&path)
+            .await
+            .unwrap()
+            .history("serde")
+            .await
+            .unwrap();
+
+        let recorded = history
+            .iter()
+            .map(|stored| {
+                (
+                    stored.repository.as_str(),
+                    stored.commit_hash.as_str(),
+                    stored.action.clone(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            recorded,
+            [
+                ("index", "c2", actions[0].clone()),
+                ("index", "c2", actions[2].clone()),
+                ("index", "c3", DiffAction::Update(change("serde", "1.0.1"))),
+            ]
+        );
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        assert!(
+            history
+                .iter()
+                .all(|stored| (now - stored.recorded_at).abs() < 60)
+        );
+    }
+}
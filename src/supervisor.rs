@@ -0,0 +1,480 @@
+use std::collections::HashMap;
+
+use ractor::{
+    Actor, ActorCell, ActorProcessingErr, ActorRef, SupervisionEvent, concurrency::Duration,
+};
+use tracing::log;
+
+use crate::actor::{IndexerActor, IndexerActorArguments, IndexerActorMessage};
+
+/// Delay before a child whose `pre_start` failed is spawned again.
+const RESTART_DELAY: Duration = Duration::from_secs(10);
+
+/// Time the children get to finish their current message when the supervisor stops.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+pub enum SupervisorMessage {
+    StartAutoIndex {
+        name: String,
+        interval: Duration,
+    },
+    StopAutoIndex {
+        name: String,
+    },
+    Index {
+        name: String,
+    },
+    /// Spawns the indexer of the named repository again, unless it's still running.
+    Restart(String),
+    /// Stops the indexer of the named repository, it isn't restarted until started again.
+    Stop {
+        name: String,
+    },
+    /// Starts the indexer of a repository stopped with [`SupervisorMessage::Stop`] again,
+    /// auto-indexing at its previous interval.
+    Start {
+        name: String,
+    },
+}
+
+/// Spawns one [`IndexerActor`] per repository and restarts them when they fail.
+///
+/// Repositories are addressed by their directory name.
+pub struct SupervisorActor;
+
+struct Child {
+    arguments: IndexerActorArguments,
+    actor: Option<ActorRef<IndexerActorMessage>>,
+    interval: Option<Duration>,
+}
+
+pub struct SupervisorState {
+    children: HashMap<String, Child>,
+    /// Repositories stopped on request, kept to be started again.
+    stopped: HashMap<String, Child>,
+}
+
+impl SupervisorState {
+    async fn spawn_child(&mut self, myself: &ActorRef<SupervisorMessage>, name: &str) {
+        let Some(child) = self.children.get_mut(name) else {
+            log::warn!("No repository named {}", name);
+            return;
+        };
+
+        match Actor::spawn_linked(
+            None,
+            IndexerActor,
+            child.arguments.clone(),
+            myself.get_cell(),
+        )
+        .await
+        {
+            Ok((actor, _)) => {
+                log::info!("Started indexer for {}", name);
+
+                // a restarted indexer has to pick up its previous interval again
+                if let Some(interval) = child.interval
+                    && let Err(e) = actor.cast(IndexerActorMessage::StartAutoIndex(interval))
+                {
+                    log::error!("Failed to start auto-indexing for {}: {}", name, e);
+                }
+
+                child.actor = Some(actor);
+            }
+            Err(e) => {
+                log::error!(
+                    "Failed to start indexer for {}, retrying in {:?}: {}",
+                    name,
+                    RESTART_DELAY,
+                    e
+                );
+
+                child.actor = None;
+                let name = name.to_string();
+                myself.send_after(RESTART_DELAY, move || SupervisorMessage::Restart(name));
+            }
+        }
+    }
+
+    fn child_name(&self, cell: &ActorCell) -> Option<String> {
+        self.children
+            .iter()
+            .find(|(_, child)| {
+                child
+                    .actor
+                    .as_ref()
+                    .is_some_and(|actor| actor.get_id() == cell.get_id())
+            })
+            .map(|(name, _)| name.clone())
+    }
+
+    fn send(&self, name: &str, message: IndexerActorMessage) {
+        match self
+            .children
+            .get(name)
+            .and_then(|child| child.actor.as_ref())
+        {
+            Some(actor) => {
+                if let Err(e) = actor.cast(message) {
+                    log::error!("Failed to send message to indexer {}: {}", name, e);
+                }
+            }
+            None => log::warn!("Indexer {} is not running", name),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Actor for SupervisorActor {
+    type State = SupervisorState;
+    type Msg = SupervisorMessage;
+    type Arguments = Vec<IndexerActorArguments>;
+
+    async fn pre_start(
+        &self,
+        _myself: ActorRef<Self::Msg>,
+        arguments: Self::Arguments,
+    ) -> Result<Self::State, ActorProcessingErr> {
+        let mut children = HashMap::new();
+
+        for arguments in arguments {
+            let name = arguments.dir_name();
+            if children.contains_key(&name) {
+                return Err(format!("Multiple repositories use the directory {}", name).into());
+            }
+
+            children.insert(
+                name,
+                Child {
+                    arguments,
+                    actor: None,
+                    interval: None,
+                },
+            );
+        }
+
+        Ok(SupervisorState {
+            children,
+            stopped: HashMap::new(),
+        })
+    }
+
+    async fn post_start(
+        &self,
+        myself: ActorRef<Self::Msg>,
+        state: &mut Self::State,
+    ) -> Result<(), ActorProcessingErr> {
+        let names = state.children.keys().cloned().collect::<Vec<_>>();
+        for name in names {
+            state.spawn_child(&myself, &name).await;
+        }
+
+        Ok(())
+    }
+
+    async fn post_stop(
+        &self,
+        myself: ActorRef<Self::Msg>,
+        _state: &mut Self::State,
+    ) -> Result<(), ActorProcessingErr> {
+        myself
+            .stop_children_and_wait(None, Some(SHUTDOWN_TIMEOUT))
+            .await;
+
+        Ok(())
+    }
+
+    async fn handle(
+        &self,
+        myself: ActorRef<Self::Msg>,
+        message: Self::Msg,
+        state: &mut Self::State,
+    ) -> Result<(), ActorProcessingErr> {
+        log::info!("Handling message: '{:?}'", message);
+
+        match message {
+            SupervisorMessage::StartAutoIndex { name, interval } => {
+                if let Some(child) = state.children.get_mut(&name) {
+                    child.interval = Some(interval);
+                }
+                state.send(&name, IndexerActorMessage::StartAutoIndex(interval));
+            }
+            SupervisorMessage::StopAutoIndex { name } => {
+                if let Some(child) = state.children.get_mut(&name) {
+                    child.interval = None;
+                }
+                state.send(&name, IndexerActorMessage::StopAutoIndex);
+            }
+            SupervisorMessage::Index { name } => {
+                state.send(&name, IndexerActorMessage::Index);
+            }
+            SupervisorMessage::Restart(name) => {
+                // a second indexer would share the directory and state of the running one
+                if state
+                    .children
+                    .get(&name)
+                    .is_some_and(|child| child.actor.is_some())
+                {
+                    log::info!("Indexer {} is already running, not restarting it", name);
+                } else {
+                    state.spawn_child(&myself, &name).await;
+                }
+            }
+            SupervisorMessage::Stop { name } => {
+                let Some(mut child) = state.children.remove(&name) else {
+                    log::warn!("No running repository named {}", name);
+                    return Ok(());
+                };
+
+                // unregistered first, so its termination isn't taken for a failure
+                if let Some(actor) = child.actor.take() {
+                    // waited for, a start right after would share its directory otherwise
+                    if let Err(e) = actor.stop_and_wait(None, Some(SHUTDOWN_TIMEOUT)).await {
+                        log::error!("Failed to wait for indexer {} to stop: {}", name, e);
+                    }
+                }
+                log::info!("Stopped indexer {}", name);
+                state.stopped.insert(name, child);
+            }
+            SupervisorMessage::Start { name } => {
+                let Some(child) = state.stopped.remove(&name) else {
+                    log::warn!("No stopped repository named {}", name);
+                    return Ok(());
+                };
+
+                state.children.insert(name.clone(), child);
+                state.spawn_child(&myself, &name).await;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn handle_supervisor_evt(
+        &self,
+        myself: ActorRef<Self::Msg>,
+        event: SupervisionEvent,
+        state: &mut Self::State,
+    ) -> Result<(), ActorProcessingErr> {
+        match event {
+            SupervisionEvent::ActorFailed(cell, err) => {
+                if let Some(name) = state.child_name(&cell) {
+                    log::error!("Indexer {} failed, restarting it: {}", name, err);
+                    state.spawn_child(&myself, &name).await;
+                }
+            }
+            SupervisionEvent::ActorTerminated(cell, _, reason) => {
+                if let Some(name) = state.child_name(&cell) {
+                    log::info!("Indexer {} stopped: {:?}", name, reason);
+                    if let Some(child) = state.children.get_mut(&name) {
+                        child.actor = None;
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::sync::mpsc::{self, Receiver};
+
+    use super::*;
+    use crate::actor::IndexerEvent;
+    use crate::crates_index::{CrateChange, DiffAction};
+    use crate::test_util::{MockBackend, TempDir, next_event};
+
+    struct Repository {
+        backend: Arc<MockBackend>,
+        events: Receiver<IndexerEvent>,
+    }
+
+    fn repository(name: &str, base_dir: &TempDir) -> (IndexerActorArguments, Repository) {
+        let backend = Arc::new(MockBackend::new(&format!("{}1", name)));
+        let (sender, events) = mpsc::channel(16);
+        let arguments = IndexerActorArguments::new(
+            format!("https://example.com/{}.git", name),
+            Some(name.to_string()),
+        )
+        .with_base_dir(base_dir.path().to_path_buf())
+        .with_backend(backend.clone())
+        .with_subscriber(sender);
+
+        (arguments, Repository { backend, events })
+    }
+
+    fn add(name: &str) -> DiffAction {
+        DiffAction::Add(CrateChange::new(name, Some("1.0.0".to_string())))
+    }
+
+    /// Events up to and including the next [`IndexerEvent::Changes`].
+    async fn events_until_changes(events: &mut Receiver<IndexerEvent>) -> Vec<IndexerEvent> {
+        let mut received = Vec::new();
+        loop {
+            let event = next_event(events).await;
+            let changes = matches!(event, IndexerEvent::Changes(_));
+            received.push(event);
+            if changes {
+                return received;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn indexes_repositories_independently() {
+        let base_dir = TempDir::new();
+        let (a_arguments, mut a) = repository("a", &base_dir);
+        let (b_arguments, mut b) = repository("b", &base_dir);
+        let (supervisor, handle) =
+            Actor::spawn(None, SupervisorActor, vec![a_arguments, b_arguments])
+                .await
+                .unwrap();
+        for repository in [&mut a, &mut b] {
+            assert!(matches!(
+                next_event(&mut repository.events).await,
+                IndexerEvent::Ready { .. }
+            ));
+        }
+
+        a.backend.push("a1", "a2", [add("serde")]);
+        supervisor
+            .cast(SupervisorMessage::Index {
+                name: "a".to_string(),
+            })
+            .unwrap();
+        let events = events_until_changes(&mut a.events).await;
+        let Some(IndexerEvent::Changes(batch)) = events.last() else {
+            unreachable!();
+        };
+        assert_eq!(
+            (batch.repository.as_str(), batch.new_commit.as_str()),
+            ("a", "a2")
+        );
+        assert_eq!(batch.actions, vec![add("serde")]);
+
+        b.backend.push("b1", "b2", [add("tokio")]);
+        supervisor
+            .cast(SupervisorMessage::Index {
+                name: "b".to_string(),
+            })
+            .unwrap();
+        let events = events_until_changes(&mut b.events).await;
+        let Some(IndexerEvent::Changes(batch)) = events.last() else {
+            unreachable!();
+        };
+        assert_eq!(
+            (batch.old_commit.as_deref(), batch.new_commit.as_str()),
+            (Some("b1"), "b2")
+        );
+        assert_eq!(batch.actions, vec![add("tokio")]);
+
+        // every index only fetched its own repository
+        assert_eq!(a.backend.state().fetches.len(), 1);
+        assert_eq!(b.backend.state().fetches.len(), 1);
+        assert!(a.events.try_recv().is_err());
+
+        supervisor.stop(None);
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn ignores_restarting_a_running_indexer() {
+        let base_dir = TempDir::new();
+        let (arguments, mut a) = repository("a", &base_dir);
+        let (supervisor, handle) = Actor::spawn(None, SupervisorActor, vec![arguments])
+            .await
+            .unwrap();
+        assert!(matches!(
+            next_event(&mut a.events).await,
+            IndexerEvent::Ready { .. }
+        ));
+
+        supervisor
+            .cast(SupervisorMessage::Restart("a".to_string()))
+            .unwrap();
+        a.backend.push("a1", "a2", [add("serde")]);
+        supervisor
+            .cast(SupervisorMessage::Index {
+                name: "a".to_string(),
+            })
+            .unwrap();
+
+        // a second indexer would have announced itself before the index was forwarded
+        let events = events_until_changes(&mut a.events).await;
+        assert_eq!(events.len(), 1, "{:?}", events);
+
+        supervisor.stop(None);
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn stops_and_starts_an_indexer() {
+        let base_dir = TempDir::new();
+        let (a_arguments, mut a) = repository("a", &base_dir);
+        let (b_arguments, mut b) = repository("b", &base_dir);
+        let (supervisor, handle) =
+            Actor::spawn(None, SupervisorActor, vec![a_arguments, b_arguments])
+                .await
+                .unwrap();
+        for repository in [&mut a, &mut b] {
+            assert!(matches!(
+                next_event(&mut repository.events).await,
+                IndexerEvent::Ready { .. }
+            ));
+        }
+
+        supervisor
+            .cast(SupervisorMessage::Stop {
+                name: "a".to_string(),
+            })
+            .unwrap();
+        a.backend.push("a1", "a2", [add("serde")]);
+        supervisor
+            .cast(SupervisorMessage::Index {
+                name: "a".to_string(),
+            })
+            .unwrap();
+        // neither indexed nor restarted once stopped
+        b.backend.push("b1", "b2", [add("tokio")]);
+        supervisor
+            .cast(SupervisorMessage::Index {
+                name: "b".to_string(),
+            })
+            .unwrap();
+        events_until_changes(&mut b.events).await;
+        assert!(a.backend.state().fetches.is_empty());
+        assert!(a.events.try_recv().is_err());
+
+        supervisor
+            .cast(SupervisorMessage::Start {
+                name: "a".to_string(),
+            })
+            .unwrap();
+        assert!(matches!(
+            next_event(&mut a.events).await,
+            IndexerEvent::Ready { .. }
+        ));
+        supervisor
+            .cast(SupervisorMessage::Index {
+                name: "a".to_string(),
+            })
+            .unwrap();
+        let events = events_until_changes(&mut a.events).await;
+        let Some(IndexerEvent::Changes(batch)) = events.last() else {
+            unreachable!();
+        };
+        assert_eq!(
+            (batch.old_commit.as_deref(), batch.new_commit.as_str()),
+            (Some("a1"), "a2")
+        );
+
+        supervisor.stop(None);
+        handle.await.unwrap();
+    }
+}
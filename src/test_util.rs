@@ -0,0 +1,321 @@
+//! Fixtures shared by the tests: temporary directories, a local remote to clone from and a
+//! scripted [`GitBackend`].
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{
+        Mutex, MutexGuard,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use tokio::sync::mpsc::Receiver;
+
+use crate::actor::{DiffBatch, IndexerEvent};
+use crate::crates_index::DiffAction;
+use crate::git::{CloneOptions, CommitMeta, FileStat, GitBackend, GitBackendKind, GitError};
+
+/// Directory below the system's temp dir, removed with everything in it once dropped.
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new() -> Self {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+        let path = std::env::temp_dir().join(format!(
+            "poll-git-test-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        // left over by an earlier run that got the same pid
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+
+        Self { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Runs git in `dir` with a fixed identity and without the user's config, returns stdout.
+pub fn git(dir: &Path, args: &[&str]) -> String {
+    let out = Command::new("git")
+        .args(args)
+        .current_dir(dir)
+        .env("GIT_CONFIG_NOSYSTEM", "1")
+        .env("GIT_CONFIG_GLOBAL", "/dev/null")
+        .env("GIT_AUTHOR_NAME", "Test Author")
+        .env("GIT_AUTHOR_EMAIL", "author@example.com")
+        .env("GIT_COMMITTER_NAME", "Test Author")
+        .env("GIT_COMMITTER_EMAIL", "author@example.com")
+        .output()
+        .unwrap();
+
+    assert!(
+        out.status.success(),
+        "git {:?} failed: {}",
+        args,
+        String::from_utf8_lossy(&out.stderr)
+    );
+
+    String::from_utf8(out.stdout).unwrap()
+}
+
+/// A repository with a working tree the tests commit to and clone from.
+pub struct Remote {
+    dir: TempDir,
+}
+
+impl Remote {
+    pub fn new() -> Self {
+        let dir = TempDir::new();
+        git(dir.path(), &["init", "-q", "-b", "master"]);
+
+        Self { dir }
+    }
+
+    pub fn path(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// `file://` url, a plain path would ignore `--depth` and `--filter`.
+    pub fn url(&self) -> String {
+        format!("file://{}", self.path().display())
+    }
+
+    /// Writes `files` and commits them, returns the new commit.
+    pub fn commit(&self, files: &[(&str, &str)], message: &str) -> String {
+        for (path, content) in files {
+            let path = self.path().join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+
+        self.commit_all(message)
+    }
+
+    fn commit_all(&self, message: &str) -> String {
+        git(self.path(), &["add", "-A"]);
+        git(
+            self.path(),
+            &["commit", "-q", "--allow-empty", "-m", message],
+        );
+
+        self.head()
+    }
+
+    pub fn head(&self) -> String {
+        git(self.path(), &["rev-parse", "HEAD"]).trim().to_string()
+    }
+}
+
+/// Every backend the crate was built with.
+pub fn backend_kinds() -> Vec<GitBackendKind> {
+    vec![
+        GitBackendKind::Cli,
+        #[cfg(feature = "libgit2")]
+        GitBackendKind::Libgit2,
+    ]
+}
+
+/// Path of `name` in the crates.io index, e.g. `se/rd/serde`.
+pub fn index_path(name: &str) -> String {
+    match name.len() {
+        1 => format!("1/{}", name),
+        2 => format!("2/{}", name),
+        3 => format!("3/{}/{}", &name[..1], name),
+        _ => format!("{}/{}/{}", &name[..2], &name[2..4], name),
+    }
+}
+
+/// A line of the crates.io index for version `vers` of `name`.
+pub fn index_line(name: &str, vers: &str, yanked: bool) -> String {
+    format!(
+        r#"{{"name":"{}","vers":"{}","deps":[{{"name":"libc","req":"^0.2","features":[],"optional":false,"default_features":true,"target":null,"kind":"normal"}}],"cksum":"8f1d1b2a6a2cbd4a241c0a4bd2fdc3e9a90e1a40c5d3a53b1f5d1ac9a8d4c6e2","features":{{}},"yanked":{},"v":2}}"#,
+        name, vers, yanked
+    )
+}
+
+/// Writes an executable `sh` script running `body` to `dir/name`.
+pub fn script(dir: &Path, name: &str, body: &str) -> PathBuf {
+    use std::os::unix::fs::PermissionsExt;
+
+    let path = dir.join(name);
+    std::fs::write(&path, format!("#!/bin/sh\n{}\n", body)).unwrap();
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    path
+}
+
+/// Next event of an indexer, fails if none arrives within a few seconds.
+pub async fn next_event(events: &mut Receiver<IndexerEvent>) -> IndexerEvent {
+    tokio::time::timeout(Duration::from_secs(10), events.recv())
+        .await
+        .expect("no event arrived")
+        .expect("the indexer dropped its subscribers")
+}
+
+/// Next [`IndexerEvent::Changes`], skipping the other events.
+pub async fn next_changes(events: &mut Receiver<IndexerEvent>) -> DiffBatch {
+    loop {
+        if let IndexerEvent::Changes(batch) = next_event(events).await {
+            return batch;
+        }
+    }
+}
+
+/// [`GitBackend`] serving scripted commits instead of running git.
+#[derive(Default)]
+pub struct MockBackend {
+    state: Mutex<MockState>,
+}
+
+/// What a [`MockBackend`] serves and the calls it received.
+#[derive(Default)]
+pub struct MockState {
+    /// Commit of the remote's `HEAD`, `FETCH_HEAD` once fetched.
+    pub head: Option<String>,
+    /// Commits of the remote's branches, `origin/<branch>` once fetched.
+    pub branches: HashMap<String, String>,
+    /// Commits revisions resolve to, e.g. the cloned `HEAD`.
+    pub revs: HashMap<String, String>,
+    /// Actions diffing `(old, new)` finds, other ranges find nothing.
+    pub diffs: HashMap<(String, String), HashSet<DiffAction>>,
+    /// Commits a force push dropped, they're no ancestor of any other commit.
+    pub rewritten: HashSet<String>,
+    /// Fails every fetch while set.
+    pub fail_fetch: bool,
+    /// Time every diff takes.
+    pub diff_delay: Duration,
+    fetch_head: Option<String>,
+    /// Branches of the fetches so far, `None` for the remote's `HEAD`.
+    pub fetches: Vec<Option<String>>,
+    /// Ranges diffed so far.
+    pub diffed: Vec<(String, String)>,
+}
+
+impl MockBackend {
+    /// A clone made at `head`, which the remote's `HEAD` points to as well.
+    pub fn new(head: &str) -> Self {
+        let backend = Self::default();
+        backend.state().head = Some(head.to_string());
+        backend
+            .state()
+            .revs
+            .insert("HEAD".to_string(), head.to_string());
+
+        backend
+    }
+
+    pub fn state(&self) -> MutexGuard<'_, MockState> {
+        self.state.lock().unwrap()
+    }
+
+    /// Moves the remote's `HEAD` to `commit`, diffing `old` against it finds `actions`.
+    pub fn push(&self, old: &str, commit: &str, actions: impl IntoIterator<Item = DiffAction>) {
+        let mut state = self.state();
+        state.head = Some(commit.to_string());
+        state.diffs.insert(
+            (old.to_string(), commit.to_string()),
+            actions.into_iter().collect(),
+        );
+    }
+}
+
+fn mock_failure(operation: &str) -> GitError {
+    GitError::CommandError(std::io::Error::other(format!("mock {} failed", operation)))
+}
+
+#[async_trait::async_trait]
+impl GitBackend for MockBackend {
+    async fn fetch(&self) -> Result<Option<String>, GitError> {
+        let mut state = self.state();
+        state.fetches.push(None);
+        if state.fail_fetch {
+            return Err(mock_failure("fetch"));
+        }
+        state.fetch_head = state.head.clone();
+
+        Ok(state.fetch_head.clone())
+    }
+
+    async fn fetch_branch(&self, branch: &str) -> Result<Option<String>, GitError> {
+        let mut state = self.state();
+        state.fetches.push(Some(branch.to_string()));
+        if state.fail_fetch {
+            return Err(mock_failure("fetch"));
+        }
+        let rev = format!("origin/{}", branch);
+        if let Some(commit) = state.branches.get(branch).cloned() {
+            state.revs.insert(rev.clone(), commit);
+        }
+
+        Ok(state.revs.get(&rev).cloned())
+    }
+
+    async fn get_current_commit_hash_from_fetch_head(&self) -> Result<Option<String>, GitError> {
+        Ok(self.state().fetch_head.clone())
+    }
+
+    async fn get_current_commit_hash_from_rev(
+        &self,
+        rev: &str,
+    ) -> Result<Option<String>, GitError> {
+        Ok(self.state().revs.get(rev).cloned())
+    }
+
+    async fn diff_commits(&self, c1: &str, c2: &str) -> Result<HashSet<DiffAction>, GitError> {
+        let delay = {
+            let mut state = self.state();
+            state.diffed.push((c1.to_string(), c2.to_string()));
+            state.diff_delay
+        };
+        tokio::time::sleep(delay).await;
+
+        let range = (c1.to_string(), c2.to_string());
+        Ok(self.state().diffs.get(&range).cloned().unwrap_or_default())
+    }
+
+    async fn diff_stats(&self, _c1: &str, _c2: &str) -> Result<Vec<FileStat>, GitError> {
+        Ok(Vec::new())
+    }
+
+    async fn get_commit_metadata(&self, rev: &str) -> Result<CommitMeta, GitError> {
+        Ok(CommitMeta {
+            hash: rev.to_string(),
+            author_name: "Mock".to_string(),
+            author_email: "mock@example.com".to_string(),
+            committed_at: None,
+            subject: format!("commit {}", rev),
+        })
+    }
+
+    async fn is_ancestor(&self, ancestor: &str, _descendant: &str) -> Result<bool, GitError> {
+        Ok(!self.state().rewritten.contains(ancestor))
+    }
+
+    async fn verify(&self) -> Result<bool, GitError> {
+        Ok(true)
+    }
+
+    async fn is_repository(&self) -> Result<bool, GitError> {
+        Ok(true)
+    }
+
+    async fn reclone(&self, _git_url: &str, _options: &CloneOptions) -> Result<(), GitError> {
+        Ok(())
+    }
+}
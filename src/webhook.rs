@@ -0,0 +1,220 @@
+use std::time::Duration;
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use tracing::log;
+
+use crate::actor::DiffBatch;
+
+/// Header carrying the HMAC-SHA256 of the body, as `sha256=<hex>`.
+pub const SIGNATURE_HEADER: &str = "X-Signature-256";
+
+const ATTEMPTS: u32 = 3;
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Posts detected changes to an HTTP endpoint.
+#[derive(Debug, Clone)]
+pub struct Webhook {
+    url: String,
+    /// Signs the body in [`SIGNATURE_HEADER`] if set.
+    secret: Option<String>,
+    client: reqwest::Client,
+}
+
+impl Webhook {
+    pub fn new(url: String, secret: Option<String>) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .unwrap_or_default();
+
+        Self {
+            url,
+            secret,
+            client,
+        }
+    }
+
+    /// Posts `batch` as JSON, retrying failed attempts. Failures are only logged.
+    pub async fn send(&self, batch: &DiffBatch) {
+        let body = match serde_json::to_vec(batch) {
+            Ok(body) => body,
+            Err(e) => {
+                log::error!("Failed to serialize webhook body: {}", e);
+                return;
+            }
+        };
+        let signature = self.secret.as_deref().map(|secret| sign(secret, &body));
+
+        for attempt in 1..=ATTEMPTS {
+            let mut request = self
+                .client
+                .post(&self.url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(body.clone());
+            if let Some(signature) = &signature {
+                request = request.header(SIGNATURE_HEADER, signature);
+            }
+
+            match request.send().await {
+                Ok(response) if response.status().is_success() => {
+                    log::debug!("Delivered webhook to {}", self.url);
+                    return;
+                }
+                Ok(response) => log::warn!(
+                    "Webhook {} answered {} (attempt {}/{})",
+                    self.url,
+                    response.status(),
+                    attempt,
+                    ATTEMPTS
+                ),
+                Err(e) => log::warn!(
+                    "Failed to deliver webhook to {} (attempt {}/{}): {}",
+                    self.url,
+                    attempt,
+                    ATTEMPTS,
+                    e
+                ),
+            }
+
+            if attempt < ATTEMPTS {
+                tokio::time::sleep(RETRY_DELAY * attempt).await;
+            }
+        }
+
+        log::error!("Giving up on webhook {} for {}", self.url, batch.new_commit);
+    }
+}
+
+/// `sha256=<hex>` of the HMAC-SHA256 of `body` keyed with `secret`.
+fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(body);
+
+    let hex = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>();
+
+    format!("sha256={}", hex)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    };
+
+    use axum::{
+        Router,
+        body::Bytes,
+        extract::State,
+        http::{HeaderMap, StatusCode},
+        routing::post,
+    };
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::crates_index::{CrateChange, DiffAction};
+
+    /// A request received by [`mock_server`]: its signature header and its body.
+    type Received = (Option<String>, Vec<u8>);
+
+    #[derive(Clone)]
+    struct MockServer {
+        requests: mpsc::UnboundedSender<Received>,
+        /// Requests still to answer with a 500.
+        failures: Arc<AtomicUsize>,
+    }
+
+    async fn hook(State(server): State<MockServer>, headers: HeaderMap, body: Bytes) -> StatusCode {
+        let signature = headers
+            .get(SIGNATURE_HEADER)
+            .map(|value| value.to_str().unwrap().to_string());
+        server.requests.send((signature, body.to_vec())).unwrap();
+
+        let failed = server
+            .failures
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| {
+                left.checked_sub(1)
+            })
+            .is_ok();
+        if failed {
+            StatusCode::INTERNAL_SERVER_ERROR
+        } else {
+            StatusCode::OK
+        }
+    }
+
+    /// Serves a webhook endpoint answering the first `failures` requests with a 500.
+    async fn mock_server(failures: usize) -> (String, mpsc::UnboundedReceiver<Received>) {
+        let (requests, received) = mpsc::unbounded_channel();
+        let router = Router::new()
+            .route("/hook", post(hook))
+            .with_state(MockServer {
+                requests,
+                failures: Arc::new(AtomicUsize::new(failures)),
+            });
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+
+        (url, received)
+    }
+
+    fn batch() -> DiffBatch {
+        DiffBatch {
+            repository: "index".to_string(),
+            old_commit: Some("c1".to_string()),
+            new_commit: "c2".to_string(),
+            actions: vec![DiffAction::Add(CrateChange::new(
+                "serde",
+                Some("1.0.0".to_string()),
+            ))],
+        }
+    }
+
+    #[test]
+    fn signs_with_hmac_sha256() {
+        assert_eq!(
+            sign("key", b"The quick brown fox jumps over the lazy dog"),
+            "sha256=f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+    }
+
+    #[tokio::test]
+    async fn posts_the_signed_batch() {
+        let (url, mut requests) = mock_server(0).await;
+
+        Webhook::new(url, Some("secret".to_string()))
+            .send(&batch())
+            .await;
+
+        let (signature, body) = requests.recv().await.unwrap();
+        assert_eq!(signature, Some(sign("secret", &body)));
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["repository"], "index");
+        assert_eq!(json["old_commit"], "c1");
+        assert_eq!(json["new_commit"], "c2");
+        assert_eq!(json["actions"].as_array().unwrap().len(), 1);
+        assert!(requests.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn retries_a_failed_delivery_without_a_signature() {
+        let (url, mut requests) = mock_server(1).await;
+
+        Webhook::new(url, None).send(&batch()).await;
+
+        let (first, _) = requests.recv().await.unwrap();
+        let (second, _) = requests.recv().await.unwrap();
+        assert_eq!((first, second), (None, None));
+        assert!(requests.try_recv().is_err());
+    }
+}